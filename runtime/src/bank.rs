@@ -54,7 +54,10 @@ use {
             calculate_stake_weighted_timestamp, MaxAllowableDrift,
             MAX_ALLOWABLE_DRIFT_PERCENTAGE_FAST, MAX_ALLOWABLE_DRIFT_PERCENTAGE_SLOW_V2,
         },
-        stakes::{InvalidCacheEntryReason, Stakes, StakesCache, StakesEnum},
+        stakes::{
+            InvalidCacheEntryReason, Redelegation, Stakes, StakesCache, StakesEnum,
+            SupermajorityChange,
+        },
         status_cache::{SlotDelta, StatusCache},
         transaction_batch::{OwnedOrBorrowed, TransactionBatch},
         verify_precompiles::verify_precompiles,
@@ -1224,7 +1227,7 @@ impl Bank {
             measure_us!(RwLock::new(parent.blockhash_queue.read().unwrap().clone()));
 
         let (stakes_cache, stakes_cache_time_us) =
-            measure_us!(StakesCache::new(parent.stakes_cache.stakes().clone()));
+            measure_us!(StakesCache::new_from_parent(&parent.stakes_cache));
 
         let (epoch_stakes, epoch_stakes_time_us) = measure_us!(parent.epoch_stakes.clone());
 
@@ -4981,6 +4984,7 @@ impl Bank {
                 self.stakes_cache.check_and_store(
                     account.pubkey(),
                     &account,
+                    self.slot(),
                     new_warmup_cooldown_rate_epoch,
                 )
             })
@@ -6260,8 +6264,12 @@ impl Bank {
             .for_each(|(pubkey, account)| {
                 // note that this could get timed to: self.rc.accounts.accounts_db.stats.stakes_cache_check_and_store_us,
                 //  but this code path is captured separately in ExecuteTimingType::UpdateStakesCacheUs
-                self.stakes_cache
-                    .check_and_store(pubkey, account, new_warmup_cooldown_rate_epoch);
+                self.stakes_cache.check_and_store(
+                    pubkey,
+                    account,
+                    self.slot(),
+                    new_warmup_cooldown_rate_epoch,
+                );
             });
     }
 
@@ -6279,6 +6287,39 @@ impl Bank {
         Some(vote_account.clone())
     }
 
+    /// Registers a callback which is invoked each time a stake account
+    /// stored in this bank, or in any bank descending from it, changes the
+    /// voter it is delegated to. See StakesCache::set_redelegation_callback.
+    pub fn set_redelegation_callback<F>(&self, callback: F)
+    where
+        F: Fn(&Redelegation) + Send + Sync + 'static,
+    {
+        self.stakes_cache.set_redelegation_callback(callback);
+    }
+
+    /// Registers a callback which is invoked each time an account stored in
+    /// this bank, or in any bank descending from it, or the epoch boundary
+    /// changes the membership of the supermajority set. See
+    /// StakesCache::observe_supermajority_change.
+    pub fn observe_supermajority_change<F>(&self, callback: F)
+    where
+        F: Fn(&SupermajorityChange) + Send + Sync + 'static,
+    {
+        self.stakes_cache.observe_supermajority_change(callback);
+    }
+
+    /// Registers a callback which is invoked each time an account stored in
+    /// this bank, or in any bank descending from it, or the epoch boundary
+    /// moves the total delegated stake by more than the given fraction. See
+    /// StakesCache::observe_total_stake_change.
+    pub fn observe_total_stake_change<F>(&self, threshold: f64, callback: F)
+    where
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        self.stakes_cache
+            .observe_total_stake_change(threshold, callback);
+    }
+
     /// Get the EpochStakes for the current Bank::epoch
     pub fn current_epoch_stakes(&self) -> &EpochStakes {
         // The stakes for a given epoch (E) in self.epoch_stakes are keyed by leader schedule epoch
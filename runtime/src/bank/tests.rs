@@ -4361,6 +4361,108 @@ fn test_bank_vote_accounts() {
     assert_eq!(vote_accounts.len(), 1);
 }
 
+#[test]
+fn test_bank_redelegation_callback() {
+    let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+    let bank = Arc::new(Bank::new_for_tests(&genesis_config));
+    let redelegations = Arc::new(std::sync::Mutex::new(Vec::new()));
+    bank.set_redelegation_callback({
+        let redelegations = redelegations.clone();
+        move |redelegation| redelegations.lock().unwrap().push(redelegation.clone())
+    });
+
+    // the callback is carried over to child banks
+    let bank = Bank::new_from_parent(bank, &Pubkey::default(), 1);
+    let vote_pubkey = solana_sdk::pubkey::new_rand();
+    let stake_pubkey = solana_sdk::pubkey::new_rand();
+    let stake_account =
+        crate::stakes::tests::create_stake_account(123, &vote_pubkey, &stake_pubkey);
+    bank.store_account(&stake_pubkey, &stake_account);
+    assert_eq!(
+        *redelegations.lock().unwrap(),
+        vec![Redelegation {
+            stake_pubkey,
+            old_voter: None,
+            new_voter: Some(vote_pubkey),
+            amount: 123,
+        }]
+    );
+}
+
+#[test]
+fn test_bank_vote_account_last_slot() {
+    let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+    let bank = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank = Bank::new_from_parent(bank, &Pubkey::default(), 7);
+    let ((vote_pubkey, vote_account), _) = crate::stakes::tests::create_staked_node_accounts(10);
+    bank.store_account(&vote_pubkey, &vote_account);
+    assert_eq!(
+        bank.stakes_cache
+            .stakes()
+            .vote_account_last_slot(&vote_pubkey),
+        Some(7)
+    );
+}
+
+#[test]
+fn test_bank_observe_supermajority_change() {
+    let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+    let bank = Arc::new(Bank::new_for_tests(&genesis_config));
+    let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    bank.observe_supermajority_change({
+        let changes = changes.clone();
+        move |change| changes.lock().unwrap().push(change.clone())
+    });
+    let old_voters = bank.stakes_cache.stakes().supermajority_set();
+
+    // the observer is carried over to child banks
+    let bank = Bank::new_from_parent(bank, &Pubkey::default(), 1);
+    let stake = 10 * bank.stakes_cache.stakes().total_stake().max(1);
+    let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+        crate::stakes::tests::create_staked_node_accounts(stake);
+    bank.store_account(&vote_pubkey, &vote_account);
+    bank.store_account(&stake_pubkey, &stake_account);
+    assert_eq!(
+        bank.stakes_cache.stakes().supermajority_set(),
+        HashSet::from([vote_pubkey])
+    );
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![SupermajorityChange {
+            added: vec![vote_pubkey],
+            removed: old_voters.into_iter().sorted().collect(),
+        }]
+    );
+}
+
+#[test]
+fn test_bank_observe_total_stake_change() {
+    let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+    let bank = Arc::new(Bank::new_for_tests(&genesis_config));
+    let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    bank.observe_total_stake_change(0.1, {
+        let changes = changes.clone();
+        move |old, new| changes.lock().unwrap().push((old, new))
+    });
+    let old_total_stake = bank.stakes_cache.stakes().total_stake();
+
+    // the observer is carried over to child banks
+    let bank = Bank::new_from_parent(bank, &Pubkey::default(), 1);
+    let stake = old_total_stake.max(1);
+    let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+        crate::stakes::tests::create_staked_node_accounts(stake);
+    bank.store_account(&vote_pubkey, &vote_account);
+    bank.store_account(&stake_pubkey, &stake_account);
+    assert_eq!(
+        *changes.lock().unwrap(),
+        vec![(old_total_stake, old_total_stake + stake)]
+    );
+    assert_eq!(
+        bank.stakes_cache.stakes().total_stake(),
+        old_total_stake + stake
+    );
+}
+
 #[test]
 fn test_bank_cloned_stake_delegations() {
     let GenesisConfigInfo {
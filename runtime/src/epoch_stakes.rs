@@ -373,8 +373,8 @@ pub(crate) mod tests {
             stake,
         );
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
 
         let stakes = Stakes::clone(&stakes_cache.stakes());
 
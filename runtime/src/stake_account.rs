@@ -35,6 +35,11 @@ pub enum Error {
 }
 
 impl<T> StakeAccount<T> {
+    #[inline]
+    pub(crate) fn account(&self) -> &AccountSharedData {
+        &self.account
+    }
+
     #[inline]
     pub(crate) fn lamports(&self) -> u64 {
         self.account.lamports()
@@ -80,6 +85,21 @@ impl TryFrom<AccountSharedData> for StakeAccount<Delegation> {
     }
 }
 
+impl TryFrom<AccountSharedData> for StakeAccount<()> {
+    type Error = Error;
+    fn try_from(account: AccountSharedData) -> Result<Self, Self::Error> {
+        if account.owner() != &solana_stake_program::id() {
+            return Err(Error::InvalidOwner(*account.owner()));
+        }
+        let stake_state: StakeStateV2 = account.state()?;
+        Ok(Self {
+            account,
+            stake_state,
+            _phantom: PhantomData,
+        })
+    }
+}
+
 impl<T> From<StakeAccount<T>> for (AccountSharedData, StakeStateV2) {
     #[inline]
     fn from(stake_account: StakeAccount<T>) -> Self {
@@ -117,3 +137,16 @@ impl AbiExample for StakeAccount<Delegation> {
         Self::try_from(AccountSharedData::from(account)).unwrap()
     }
 }
+
+#[cfg(feature = "frozen-abi")]
+impl AbiExample for StakeAccount<()> {
+    fn example() -> Self {
+        use solana_sdk::{account::Account, stake::state::Meta};
+        let stake_state = StakeStateV2::Initialized(Meta::example());
+        let mut account = Account::example();
+        account.data.resize(200, 0u8);
+        account.owner = solana_stake_program::id();
+        account.set_state(&stake_state).unwrap();
+        Self::try_from(AccountSharedData::from(account)).unwrap()
+    }
+}
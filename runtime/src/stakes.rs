@@ -4,23 +4,30 @@ use {
     crate::{stake_account, stake_history::StakeHistory},
     dashmap::DashMap,
     im::HashMap as ImHashMap,
+    itertools::Itertools,
     log::error,
     num_derive::ToPrimitive,
     num_traits::ToPrimitive,
+    rand::Rng,
     rayon::{prelude::*, ThreadPool},
     solana_accounts_db::stake_rewards::StakeReward,
     solana_sdk::{
-        account::{AccountSharedData, ReadableAccount},
+        account::{accounts_equal, AccountSharedData, ReadableAccount, WritableAccount},
+        account_utils::StateMut,
         clock::{Epoch, Slot},
         pubkey::Pubkey,
-        stake::state::{Delegation, StakeActivationStatus},
-        vote::state::VoteStateVersions,
+        stake::{
+            stake_flags::StakeFlags,
+            state::{Delegation, Meta, StakeActivationStatus, StakeStateV2},
+        },
+        vote::state::{VoteState, VoteStateVersions},
     },
     solana_stake_program::stake_state::Stake,
-    solana_vote::vote_account::{VoteAccount, VoteAccounts},
+    solana_vote::vote_account::{VoteAccount, VoteAccounts, VoteAccountsHashMap},
     std::{
-        collections::HashMap,
-        ops::Add,
+        collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+        hash::{Hash, Hasher},
+        ops::{Add, BitXor},
         sync::{Arc, RwLock, RwLockReadGuard},
     },
     thiserror::Error,
@@ -40,10 +47,38 @@ pub enum Error {
     StakeAccountNotFound(Pubkey),
     #[error("Vote account mismatch: {0}")]
     VoteAccountMismatch(Pubkey),
+    // Not named source and destination, since thiserror would treat a field
+    // named source as the underlying error.
+    #[error("Merge voter mismatch: {source_voter} != {destination_voter}")]
+    MergeVoterMismatch {
+        source_voter: Pubkey,
+        destination_voter: Pubkey,
+    },
+    #[error("Stake account split below rent exempt reserve: {0}")]
+    SplitBelowRentExemptReserve(Pubkey),
+    #[error("Cannot merge stake account into itself: {0}")]
+    MergeSelf(Pubkey),
+    #[error("Incompatible stake accounts to merge: {source_account} into {destination_account}")]
+    MergeIncompatible {
+        source_account: Pubkey,
+        destination_account: Pubkey,
+    },
     #[error("Vote account not cached: {0}")]
     VoteAccountNotCached(Pubkey),
     #[error("Vote account not found: {0}")]
     VoteAccountNotFound(Pubkey),
+    #[error("Failed to deserialize stakes: {0}")]
+    DeserializeError(#[from] bincode::Error),
+    #[error("Vote account stake mismatch: {0}")]
+    VoteAccountStakeMismatch(Pubkey),
+    #[error("Delegated stake overflow: {voter}")]
+    DelegatedStakeOverflow { voter: Pubkey },
+    #[error("Stakes checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Unsupported checked stakes version: {0}")]
+    UnsupportedVersion(u32),
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, ToPrimitive)]
@@ -54,91 +89,359 @@ pub enum InvalidCacheEntryReason {
 }
 
 type StakeAccount = stake_account::StakeAccount<Delegation>;
+type UndelegatedStakeAccount = stake_account::StakeAccount<()>;
 
 #[cfg_attr(feature = "frozen-abi", derive(AbiExample))]
 #[derive(Default, Debug)]
-pub(crate) struct StakesCache(RwLock<Stakes<StakeAccount>>);
+pub struct StakesCache {
+    stakes: RwLock<Stakes<StakeAccount>>,
+    /// stake accounts which carry no delegation, e.g.
+    /// StakeStateV2::Initialized. These contribute no stake, so they are
+    /// neither part of the stakes nor of the snapshot format, and only cover
+    /// the accounts stored since the node started.
+    undelegated_stake_accounts: RwLock<ImHashMap<Pubkey, UndelegatedStakeAccount>>,
+    /// invoked whenever a stake account changes its delegated voter, see
+    /// StakesCache::set_redelegation_callback.
+    redelegation_callback: RwLock<Option<RedelegationCallback>>,
+    /// voters whose delegated stake is not counted by the queries of the
+    /// cache, see StakesCache::set_excluded_voters.
+    excluded_voters: RwLock<Arc<HashSet<Pubkey>>>,
+    /// invoked whenever the membership of the supermajority set changes, see
+    /// StakesCache::observe_supermajority_change.
+    supermajority_observer: RwLock<Option<SupermajorityObserver>>,
+    /// invoked whenever the total delegated stake moves by more than a
+    /// threshold, see StakesCache::observe_total_stake_change.
+    total_stake_observer: RwLock<Option<TotalStakeObserver>>,
+}
 
 impl StakesCache {
     pub(crate) fn new(stakes: Stakes<StakeAccount>) -> Self {
-        Self(RwLock::new(stakes))
+        Self {
+            stakes: RwLock::new(stakes),
+            undelegated_stake_accounts: RwLock::default(),
+            redelegation_callback: RwLock::default(),
+            excluded_voters: RwLock::default(),
+            supermajority_observer: RwLock::default(),
+            total_stake_observer: RwLock::default(),
+        }
+    }
+
+    /// Creates the stakes cache of a child bank, carrying over the stakes,
+    /// the undelegated stake accounts, the redelegation callback, the
+    /// excluded voters and the observers of the parent bank.
+    pub(crate) fn new_from_parent(parent: &Self) -> Self {
+        // The observers record the state of the stakes they were last
+        // updated with, so keep the stakes locked while copying them.
+        let stakes = parent.stakes();
+        Self {
+            stakes: RwLock::new(stakes.clone()),
+            undelegated_stake_accounts: RwLock::new(parent.undelegated_stake_accounts().clone()),
+            redelegation_callback: RwLock::new(
+                parent.redelegation_callback.read().unwrap().clone(),
+            ),
+            excluded_voters: RwLock::new(parent.excluded_voters.read().unwrap().clone()),
+            supermajority_observer: RwLock::new(
+                parent.supermajority_observer.read().unwrap().clone(),
+            ),
+            total_stake_observer: RwLock::new(parent.total_stake_observer.read().unwrap().clone()),
+        }
+    }
+
+    /// Registers a callback which is invoked, after storing an account, each
+    /// time a stake account changes the voter it is delegated to; including
+    /// when it becomes delegated or is no longer delegated. The callback runs
+    /// after releasing the lock on the stakes. It is carried over to the
+    /// caches of child banks, so each fork reports the redelegations stored
+    /// on that fork.
+    pub fn set_redelegation_callback<F>(&self, callback: F)
+    where
+        F: Fn(&Redelegation) + Send + Sync + 'static,
+    {
+        *self.redelegation_callback.write().unwrap() =
+            Some(RedelegationCallback(Arc::new(callback)));
+    }
+
+    /// Excludes the given voters, e.g. a bootstrap validator on test clusters,
+    /// from the stake reported by StakesCache::delegated_stake and
+    /// StakesCache::total_stake, replacing the previously excluded voters.
+    /// The cached stakes, and hence the snapshot, the epoch stakes and the
+    /// leader schedule, still account for the stake delegated to them. The
+    /// excluded voters are carried over to the caches of child banks.
+    pub fn set_excluded_voters(&self, excluded_voters: HashSet<Pubkey>) {
+        *self.excluded_voters.write().unwrap() = Arc::new(excluded_voters);
+    }
+
+    /// Delegated stake of the given voter, or zero if it is excluded, see
+    /// StakesCache::set_excluded_voters.
+    pub fn delegated_stake(&self, voter_pubkey: &Pubkey) -> u64 {
+        if self.excluded_voters.read().unwrap().contains(voter_pubkey) {
+            return 0;
+        }
+        self.stakes()
+            .vote_accounts
+            .get_delegated_stake(voter_pubkey)
+    }
+
+    /// Total delegated stake of the voters which are not excluded, see
+    /// StakesCache::set_excluded_voters.
+    pub fn total_stake(&self) -> u64 {
+        let excluded_voters = self.excluded_voters.read().unwrap().clone();
+        self.stakes().total_stake_excluding(&excluded_voters)
+    }
+
+    /// Registers a callback which is invoked, after storing an account or
+    /// updating the stakes at the epoch boundary, each time the membership
+    /// of the supermajority set changes. Storing an account only recomputes
+    /// the supermajority set if the stake of the voters it touches may have
+    /// moved a voter across the boundary of the set, see
+    /// SupermajorityObserver::apply_stake_changes. The callback runs after
+    /// releasing the lock on the stakes. It is carried over to the caches of
+    /// child banks, so each fork reports the changes stored on that fork.
+    pub fn observe_supermajority_change<F>(&self, callback: F)
+    where
+        F: Fn(&SupermajorityChange) + Send + Sync + 'static,
+    {
+        // Keep the stakes locked until the observer is registered, so that
+        // it does not miss any stake changes.
+        let stakes = self.stakes();
+        let observer = SupermajorityObserver::new(Arc::new(callback), &stakes);
+        *self.supermajority_observer.write().unwrap() = Some(observer);
+    }
+
+    /// Registers a callback which is invoked, after storing an account or
+    /// updating the stakes at the epoch boundary, each time the total
+    /// delegated stake moves by more than the given fraction of the total
+    /// delegated stake it was last notified of, or of the total delegated
+    /// stake when registered. The callback is passed the previous and the new
+    /// total stake. Storing an account updates the total stake by the change
+    /// of the stake of the voters it touches, rather than summing the stake
+    /// of all voters. The callback runs after releasing the lock on the
+    /// stakes. It is carried over to the caches of child banks, so each fork
+    /// reports the changes stored on that fork.
+    pub fn observe_total_stake_change<F>(&self, threshold: f64, callback: F)
+    where
+        F: Fn(u64, u64) + Send + Sync + 'static,
+    {
+        // Keep the stakes locked until the observer is registered, so that
+        // it does not miss any stake changes.
+        let stakes = self.stakes();
+        let total_stake = stakes.total_stake();
+        *self.total_stake_observer.write().unwrap() = Some(TotalStakeObserver {
+            callback: Arc::new(callback),
+            threshold,
+            total_stake,
+            current_total_stake: total_stake,
+        });
+    }
+
+    fn is_observed(&self) -> bool {
+        self.supermajority_observer.read().unwrap().is_some()
+            || self.total_stake_observer.read().unwrap().is_some()
+    }
+
+    /// Updates the registered observers with the given stakes, returning the
+    /// callbacks to invoke. Must be called while holding the write lock on
+    /// the stakes, so that the observers see every change exactly once.
+    /// Given the changes of the delegated stake made by storing a single
+    /// account, the supermajority set is only recomputed if needed and the
+    /// total stake is updated incrementally; without them, e.g. at the epoch
+    /// boundary, both are always recomputed.
+    fn update_observers(
+        &self,
+        stakes: &Stakes<StakeAccount>,
+        stake_changes: Option<&[VoterStakeChange]>,
+    ) -> ObserverNotifications {
+        if stake_changes.is_some_and(<[_]>::is_empty) {
+            return ObserverNotifications::default();
+        }
+        let supermajority_change = self
+            .supermajority_observer
+            .write()
+            .unwrap()
+            .as_mut()
+            .and_then(|observer| {
+                if let Some(stake_changes) = stake_changes {
+                    if observer.apply_stake_changes(stake_changes) {
+                        return None;
+                    }
+                }
+                let change = observer.update(stakes)?;
+                Some((observer.callback.clone(), change))
+            });
+        let total_stake_change = self
+            .total_stake_observer
+            .write()
+            .unwrap()
+            .as_mut()
+            .and_then(|observer| {
+                let total_stake = match stake_changes {
+                    Some(stake_changes) => observer.total_stake_with(stake_changes),
+                    None => stakes.total_stake(),
+                };
+                let old_total_stake = observer.update(total_stake)?;
+                Some((observer.callback.clone(), old_total_stake, total_stake))
+            });
+        ObserverNotifications {
+            supermajority_change,
+            total_stake_change,
+        }
     }
 
     pub(crate) fn stakes(&self) -> RwLockReadGuard<Stakes<StakeAccount>> {
-        self.0.read().unwrap()
+        self.stakes.read().unwrap()
+    }
+
+    /// Stake accounts which are cached but not delegated, e.g.
+    /// StakeStateV2::Initialized. These never contribute to any voter's stake.
+    pub fn undelegated_stake_accounts(
+        &self,
+    ) -> RwLockReadGuard<ImHashMap<Pubkey, UndelegatedStakeAccount>> {
+        self.undelegated_stake_accounts.read().unwrap()
+    }
+
+    /// Counts the cached stake accounts, delegated or not, by their stake
+    /// state variant, using the stake states already deserialized when
+    /// caching the accounts.
+    pub fn stake_state_counts(&self) -> StakeStateCounts {
+        let stakes = self.stakes();
+        let undelegated_stake_accounts = self.undelegated_stake_accounts();
+        let stake_states = stakes
+            .stake_delegations
+            .values()
+            .map(StakeAccount::stake_state)
+            .chain(
+                undelegated_stake_accounts
+                    .values()
+                    .map(UndelegatedStakeAccount::stake_state),
+            );
+        let mut counts = StakeStateCounts::default();
+        for stake_state in stake_states {
+            match stake_state {
+                StakeStateV2::Uninitialized => counts.uninitialized += 1,
+                StakeStateV2::Initialized(_) => counts.initialized += 1,
+                StakeStateV2::Stake(..) => counts.stake += 1,
+                StakeStateV2::RewardsPool => counts.rewards_pool += 1,
+            }
+        }
+        counts
+    }
+
+    /// Returns the cached stake accounts, delegated or not, grouped by their
+    /// authorized staker, each group sorted by pubkey. Stake accounts without
+    /// an authorized staker, e.g. StakeStateV2::Uninitialized, are skipped.
+    pub fn stake_accounts_by_staker(&self) -> HashMap</*staker:*/ Pubkey, Vec<Pubkey>> {
+        let stakes = self.stakes();
+        let undelegated_stake_accounts = self.undelegated_stake_accounts();
+        let stake_states = stakes
+            .stake_delegations
+            .iter()
+            .map(|(pubkey, stake_account)| (pubkey, stake_account.stake_state()))
+            .chain(
+                undelegated_stake_accounts
+                    .iter()
+                    .map(|(pubkey, stake_account)| (pubkey, stake_account.stake_state())),
+            );
+        let mut stake_accounts = HashMap::<Pubkey, Vec<Pubkey>>::new();
+        for (pubkey, stake_state) in stake_states {
+            if let Some(authorized) = stake_state.authorized() {
+                stake_accounts
+                    .entry(authorized.staker)
+                    .or_default()
+                    .push(*pubkey);
+            }
+        }
+        for pubkeys in stake_accounts.values_mut() {
+            pubkeys.sort_unstable();
+        }
+        stake_accounts
     }
 
+    /// Updates the cache with the given account stored at the given slot.
+    /// If the account is cached as a vote account, the slot is recorded,
+    /// see Stakes::vote_account_last_slot.
     pub(crate) fn check_and_store(
         &self,
         pubkey: &Pubkey,
         account: &impl ReadableAccount,
+        slot: Slot,
         new_rate_activation_epoch: Option<Epoch>,
     ) {
         // TODO: If the account is already cached as a vote or stake account
         // but the owner changes, then this needs to evict the account from
         // the cache. see:
         // https://github.com/solana-labs/solana/pull/24200#discussion_r849935444
-        let owner = account.owner();
-        // Zero lamport accounts are not stored in accounts-db
-        // and so should be removed from cache as well.
-        if account.lamports() == 0 {
-            if solana_vote_program::check_id(owner) {
-                let _old_vote_account = {
-                    let mut stakes = self.0.write().unwrap();
-                    stakes.remove_vote_account(pubkey)
-                };
-            } else if solana_stake_program::check_id(owner) {
-                let mut stakes = self.0.write().unwrap();
-                stakes.remove_stake_delegation(pubkey, new_rate_activation_epoch);
-            }
+        let Some(update) = StakesCacheUpdate::new(account) else {
             return;
-        }
-        debug_assert_ne!(account.lamports(), 0u64);
-        if solana_vote_program::check_id(owner) {
-            if VoteStateVersions::is_correct_size_and_initialized(account.data()) {
-                match VoteAccount::try_from(account.to_account_shared_data()) {
-                    Ok(vote_account) => {
-                        // drop the old account after releasing the lock
-                        let _old_vote_account = {
-                            let mut stakes = self.0.write().unwrap();
-                            stakes.upsert_vote_account(
-                                pubkey,
-                                vote_account,
-                                new_rate_activation_epoch,
-                            )
-                        };
-                    }
-                    Err(_) => {
-                        // drop the old account after releasing the lock
-                        let _old_vote_account = {
-                            let mut stakes = self.0.write().unwrap();
-                            stakes.remove_vote_account(pubkey)
-                        };
-                    }
-                }
-            } else {
-                // drop the old account after releasing the lock
-                let _old_vote_account = {
-                    let mut stakes = self.0.write().unwrap();
-                    stakes.remove_vote_account(pubkey)
-                };
-            };
-        } else if solana_stake_program::check_id(owner) {
-            match StakeAccount::try_from(account.to_account_shared_data()) {
-                Ok(stake_account) => {
-                    let mut stakes = self.0.write().unwrap();
-                    stakes.upsert_stake_delegation(
-                        *pubkey,
-                        stake_account,
-                        new_rate_activation_epoch,
-                    );
+        };
+        let redelegation_callback = self.redelegation_callback.read().unwrap().clone();
+        // Only look up the delegations if there is a callback to notify.
+        let get_delegation = |stakes: &Stakes<StakeAccount>| {
+            redelegation_callback.as_ref()?;
+            stakes
+                .stake_delegations
+                .get(pubkey)
+                .map(StakeAccount::delegation)
+                .copied()
+        };
+        // drop the old account after releasing the lock
+        let (_old_vote_account, redelegation, notifications) = {
+            let mut stakes = self.stakes.write().unwrap();
+            // Vote account updates never touch the undelegated stake
+            // accounts, so only stake account updates take that lock.
+            let update = match update {
+                StakesCacheUpdate::UpsertUndelegatedStakeAccount(stake_account) => {
+                    self.undelegated_stake_accounts
+                        .write()
+                        .unwrap()
+                        .insert(*pubkey, stake_account);
+                    // A previously delegated account no longer contributes
+                    // any stake.
+                    StakesCacheUpdate::RemoveStakeDelegation
                 }
-                Err(_) => {
-                    let mut stakes = self.0.write().unwrap();
-                    stakes.remove_stake_delegation(pubkey, new_rate_activation_epoch);
+                update @ (StakesCacheUpdate::UpsertStakeDelegation(_)
+                | StakesCacheUpdate::RemoveStakeDelegation) => {
+                    self.undelegated_stake_accounts
+                        .write()
+                        .unwrap()
+                        .remove(pubkey);
+                    update
                 }
-            }
+                update => update,
+            };
+            let old_delegation = get_delegation(&stakes);
+            // Only look up the delegated stakes if there is an observer to
+            // notify.
+            let old_stakes = self
+                .is_observed()
+                .then(|| stakes.touched_voter_stakes(pubkey, &update));
+            let old_vote_account = stakes.apply_update(pubkey, update, new_rate_activation_epoch);
+            stakes.record_vote_account_slot(pubkey, account, slot);
+            let redelegation = Redelegation::new(*pubkey, old_delegation, get_delegation(&stakes));
+            let notifications = old_stakes
+                .map(|old_stakes| {
+                    let stake_changes: Vec<_> = old_stakes
+                        .into_iter()
+                        .filter_map(|(voter_pubkey, old_stake)| {
+                            let new_stake = stakes.vote_accounts.get_delegated_stake(&voter_pubkey);
+                            (new_stake != old_stake).then_some(VoterStakeChange {
+                                voter_pubkey,
+                                old_stake,
+                                new_stake,
+                            })
+                        })
+                        .collect();
+                    self.update_observers(&stakes, Some(&stake_changes))
+                })
+                .unwrap_or_default();
+            (old_vote_account, redelegation, notifications)
+        };
+        // The locks are released, so the callback may read the cache.
+        if let (Some(RedelegationCallback(callback)), Some(redelegation)) =
+            (redelegation_callback, redelegation)
+        {
+            callback(&redelegation);
         }
+        notifications.notify();
     }
 
     pub(crate) fn activate_epoch(
@@ -147,8 +450,12 @@ impl StakesCache {
         thread_pool: &ThreadPool,
         new_rate_activation_epoch: Option<Epoch>,
     ) {
-        let mut stakes = self.0.write().unwrap();
-        stakes.activate_epoch(next_epoch, thread_pool, new_rate_activation_epoch)
+        let notifications = {
+            let mut stakes = self.stakes.write().unwrap();
+            stakes.activate_epoch(next_epoch, thread_pool, new_rate_activation_epoch);
+            self.update_observers(&stakes, None)
+        };
+        notifications.notify();
     }
 
     pub(crate) fn update_stake_accounts(
@@ -157,11 +464,38 @@ impl StakesCache {
         stake_rewards: &[StakeReward],
         new_rate_activation_epoch: Option<Epoch>,
     ) {
-        self.0.write().unwrap().update_stake_accounts(
-            thread_pool,
-            stake_rewards,
-            new_rate_activation_epoch,
-        )
+        let notifications = {
+            let mut stakes = self.stakes.write().unwrap();
+            stakes.update_stake_accounts(thread_pool, stake_rewards, new_rate_activation_epoch);
+            self.update_observers(&stakes, None)
+        };
+        notifications.notify();
+    }
+
+    /// Same as StakesCache::activate_epoch followed by
+    /// StakesCache::update_stake_accounts, while also pruning the stake
+    /// delegations below prune_below lamports, see
+    /// Stakes::reconcile_epoch_boundary.
+    pub fn reconcile_epoch_boundary(
+        &self,
+        next_epoch: Epoch,
+        stake_rewards: &[StakeReward],
+        prune_below: Option<u64>,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        let notifications = {
+            let mut stakes = self.stakes.write().unwrap();
+            stakes.reconcile_epoch_boundary(
+                next_epoch,
+                stake_rewards,
+                prune_below,
+                thread_pool,
+                new_rate_activation_epoch,
+            );
+            self.update_observers(&stakes, None)
+        };
+        notifications.notify();
     }
 
     pub(crate) fn handle_invalid_keys(
@@ -175,16 +509,102 @@ impl StakesCache {
 
         // Prune invalid stake delegations and vote accounts that were
         // not properly evicted in normal operation.
-        let mut stakes = self.0.write().unwrap();
-
-        for (vote_pubkey, reason) in invalid_vote_keys {
-            stakes.remove_vote_account(&vote_pubkey);
-            datapoint_warn!(
-                "bank-stake_delegation_accounts-invalid-account",
-                ("slot", current_slot as i64, i64),
-                ("vote-address", format!("{vote_pubkey:?}"), String),
-                ("reason", reason.to_i64().unwrap_or_default(), i64),
-            );
+        let notifications = {
+            let mut stakes = self.stakes.write().unwrap();
+
+            for (vote_pubkey, reason) in invalid_vote_keys {
+                stakes.remove_vote_account(&vote_pubkey);
+                datapoint_warn!(
+                    "bank-stake_delegation_accounts-invalid-account",
+                    ("slot", current_slot as i64, i64),
+                    ("vote-address", format!("{vote_pubkey:?}"), String),
+                    ("reason", reason.to_i64().unwrap_or_default(), i64),
+                );
+            }
+            self.update_observers(&stakes, None)
+        };
+        notifications.notify();
+    }
+}
+
+/// Parses the given account as a vote account without caching it, e.g. to
+/// read the node identity or commission of a vote account which is not
+/// cached. Unlike the stakes cache, this also accepts initialized vote states
+/// in the legacy V0_23_5 layout; the cache keeps rejecting those, since which
+/// vote accounts are cached determines the stakes of the bank.
+pub fn parse_vote_account(account: &impl ReadableAccount) -> Option<VoteAccount> {
+    if !solana_vote_program::check_id(account.owner())
+        || account.lamports() == 0
+        || !is_initialized_vote_state(account.data())
+    {
+        return None;
+    }
+    VoteAccount::try_from(account.to_account_shared_data()).ok()
+}
+
+/// Returns true if the account data holds an initialized vote state of any
+/// version. VoteStateVersions::is_correct_size_and_initialized does not cover
+/// the legacy V0_23_5 layout, which VoteAccount can still deserialize, so such
+/// accounts are checked by deserializing them.
+fn is_initialized_vote_state(data: &[u8]) -> bool {
+    const V0_23_5_TAG: [u8; 4] = 0u32.to_le_bytes();
+    VoteStateVersions::is_correct_size_and_initialized(data)
+        || (data.starts_with(&V0_23_5_TAG)
+            && bincode::deserialize::<VoteStateVersions>(data)
+                .map(|versions| !versions.is_uninitialized())
+                .unwrap_or_default())
+}
+
+/// An update to the stakes cache derived from a single account, computed
+/// before acquiring the write lock on the cache.
+enum StakesCacheUpdate {
+    UpsertVoteAccount(VoteAccount),
+    RemoveVoteAccount,
+    UpsertStakeDelegation(StakeAccount),
+    UpsertUndelegatedStakeAccount(UndelegatedStakeAccount),
+    RemoveStakeDelegation,
+}
+
+impl StakesCacheUpdate {
+    /// Returns None if the account is neither a vote nor a stake account.
+    fn new(account: &impl ReadableAccount) -> Option<Self> {
+        let owner = account.owner();
+        // Zero lamport accounts are not stored in accounts-db
+        // and so should be removed from cache as well.
+        if account.lamports() == 0 {
+            if solana_vote_program::check_id(owner) {
+                return Some(Self::RemoveVoteAccount);
+            } else if solana_stake_program::check_id(owner) {
+                return Some(Self::RemoveStakeDelegation);
+            }
+            return None;
+        }
+        debug_assert_ne!(account.lamports(), 0u64);
+        if solana_vote_program::check_id(owner) {
+            if !VoteStateVersions::is_correct_size_and_initialized(account.data()) {
+                return Some(Self::RemoveVoteAccount);
+            }
+            match VoteAccount::try_from(account.to_account_shared_data()) {
+                Ok(vote_account) => Some(Self::UpsertVoteAccount(vote_account)),
+                Err(_) => Some(Self::RemoveVoteAccount),
+            }
+        } else if solana_stake_program::check_id(owner) {
+            match StakeAccount::try_from(account.to_account_shared_data()) {
+                Ok(stake_account) => Some(Self::UpsertStakeDelegation(stake_account)),
+                Err(stake_account::Error::InvalidDelegation(_)) => {
+                    // The account holds a valid stake state without a
+                    // delegation, e.g. StakeStateV2::Initialized.
+                    match UndelegatedStakeAccount::try_from(account.to_account_shared_data()) {
+                        Ok(stake_account) => {
+                            Some(Self::UpsertUndelegatedStakeAccount(stake_account))
+                        }
+                        Err(_) => Some(Self::RemoveStakeDelegation),
+                    }
+                }
+                Err(_) => Some(Self::RemoveStakeDelegation),
+            }
+        } else {
+            None
         }
     }
 }
@@ -197,7 +617,7 @@ impl StakesCache {
 /// the need to load the stake account from accounts-db when working with
 /// stake-delegations.
 #[cfg_attr(feature = "frozen-abi", derive(AbiExample))]
-#[derive(Default, Clone, PartialEq, Debug, Deserialize, Serialize)]
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct Stakes<T: Clone> {
     /// vote accounts
     vote_accounts: VoteAccounts,
@@ -213,6 +633,350 @@ pub struct Stakes<T: Clone> {
 
     /// history of staking levels
     stake_history: StakeHistory,
+
+    /// slot at which each vote account was last stored through
+    /// Stakes::store_at_slot.
+    #[serde(skip)]
+    vote_account_slots: ImHashMap</*voter:*/ Pubkey, Slot>,
+
+    /// number of consecutive epochs, as of the last epoch activation, each
+    /// vote account has had no delegated stake, see Stakes::evict_idle_voters.
+    #[serde(skip)]
+    idle_epochs: ImHashMap</*voter:*/ Pubkey, /*epochs:*/ u64>,
+}
+
+/// A change of the voter a stake account is delegated to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redelegation {
+    pub stake_pubkey: Pubkey,
+    /// None if the stake account was not delegated before.
+    pub old_voter: Option<Pubkey>,
+    /// None if the stake account is no longer delegated.
+    pub new_voter: Option<Pubkey>,
+    /// delegated lamports, i.e. Delegation::stake.
+    pub amount: u64,
+}
+
+impl Redelegation {
+    /// Returns the change from old_delegation to new_delegation of the stake
+    /// account, or None if it is still delegated to the same voter.
+    fn new(
+        stake_pubkey: Pubkey,
+        old_delegation: Option<Delegation>,
+        new_delegation: Option<Delegation>,
+    ) -> Option<Self> {
+        let old_voter = old_delegation.map(|delegation| delegation.voter_pubkey);
+        let new_voter = new_delegation.map(|delegation| delegation.voter_pubkey);
+        if old_voter == new_voter {
+            return None;
+        }
+        Some(Self {
+            stake_pubkey,
+            old_voter,
+            new_voter,
+            amount: new_delegation.or(old_delegation)?.stake,
+        })
+    }
+}
+
+/// Callback registered through StakesCache::set_redelegation_callback.
+#[derive(Clone)]
+pub struct RedelegationCallback(Arc<dyn Fn(&Redelegation) + Send + Sync>);
+
+impl std::fmt::Debug for RedelegationCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RedelegationCallback")
+    }
+}
+
+#[cfg(feature = "frozen-abi")]
+impl solana_frozen_abi::abi_example::AbiExample for RedelegationCallback {
+    fn example() -> Self {
+        Self(Arc::new(|_: &Redelegation| {}))
+    }
+}
+
+/// A change of a single stake delegation between two snapshots of the
+/// stakes, see Stakes::delegation_changes. Stake amounts are the delegated
+/// stakes, not accounting for activation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DelegationChange {
+    Added {
+        stake_pubkey: Pubkey,
+        voter_pubkey: Pubkey,
+        stake: u64,
+    },
+    Removed {
+        stake_pubkey: Pubkey,
+        voter_pubkey: Pubkey,
+        stake: u64,
+    },
+    Redelegated {
+        stake_pubkey: Pubkey,
+        old_voter_pubkey: Pubkey,
+        new_voter_pubkey: Pubkey,
+        old_stake: u64,
+        new_stake: u64,
+    },
+    StakeChanged {
+        stake_pubkey: Pubkey,
+        voter_pubkey: Pubkey,
+        old_stake: u64,
+        new_stake: u64,
+    },
+}
+
+/// The change of the delegated stake of a vote account between two
+/// snapshots of the stakes, see Stakes::stake_trends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StakeTrend {
+    /// delegated stake increased by the given lamports.
+    Up(u64),
+    /// delegated stake decreased by the given lamports.
+    Down(u64),
+    Flat,
+}
+
+/// A change of the membership of the supermajority set, see
+/// Stakes::supermajority_set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SupermajorityChange {
+    /// voters which joined the supermajority set, sorted by pubkey.
+    pub added: Vec<Pubkey>,
+    /// voters which left the supermajority set, sorted by pubkey.
+    pub removed: Vec<Pubkey>,
+}
+
+/// A change of the delegated stake of a single voter made by storing an
+/// account.
+struct VoterStakeChange {
+    voter_pubkey: Pubkey,
+    old_stake: u64,
+    new_stake: u64,
+}
+
+/// Callbacks of the observers to invoke once the lock on the stakes is
+/// released, see StakesCache::update_observers.
+#[derive(Default)]
+struct ObserverNotifications {
+    supermajority_change: Option<(
+        Arc<dyn Fn(&SupermajorityChange) + Send + Sync>,
+        SupermajorityChange,
+    )>,
+    total_stake_change: Option<(
+        Arc<dyn Fn(/*old:*/ u64, /*new:*/ u64) + Send + Sync>,
+        /*old:*/ u64,
+        /*new:*/ u64,
+    )>,
+}
+
+impl ObserverNotifications {
+    /// Must be called after releasing the lock on the stakes, so that the
+    /// callbacks may read the cache.
+    fn notify(self) {
+        if let Some((callback, change)) = self.supermajority_change {
+            callback(&change);
+        }
+        if let Some((callback, old_total_stake, total_stake)) = self.total_stake_change {
+            callback(old_total_stake, total_stake);
+        }
+    }
+}
+
+/// Callback registered through StakesCache::observe_supermajority_change,
+/// along with the supermajority set it was last notified of, and bounds on
+/// the delegated stakes which tell whether the set may have changed since.
+#[derive(Clone)]
+pub struct SupermajorityObserver {
+    callback: Arc<dyn Fn(&SupermajorityChange) + Send + Sync>,
+    voters: HashSet<Pubkey>,
+    /// total delegated stake.
+    total_stake: u64,
+    /// delegated stake of the voters in the set.
+    supermajority_stake: u64,
+    /// at most the lowest delegated stake of the voters in the set.
+    min_member_stake: u64,
+    /// at least the highest delegated stake of the voters outside the set.
+    max_other_stake: u64,
+}
+
+impl SupermajorityObserver {
+    fn new(
+        callback: Arc<dyn Fn(&SupermajorityChange) + Send + Sync>,
+        stakes: &Stakes<StakeAccount>,
+    ) -> Self {
+        let mut observer = Self {
+            callback,
+            voters: HashSet::default(),
+            total_stake: 0,
+            supermajority_stake: 0,
+            min_member_stake: 0,
+            max_other_stake: 0,
+        };
+        observer.update(stakes);
+        observer
+    }
+
+    /// Applies the given stake changes to the recorded bounds, returning
+    /// true if the supermajority set certainly did not change, i.e. its
+    /// voters still hold more stake than any other voter, and they still are
+    /// the fewest voters holding more than two thirds of the total stake.
+    /// Otherwise the set needs to be recomputed.
+    fn apply_stake_changes(&mut self, stake_changes: &[VoterStakeChange]) -> bool {
+        for change in stake_changes {
+            self.total_stake = self
+                .total_stake
+                .saturating_sub(change.old_stake)
+                .saturating_add(change.new_stake);
+            if self.voters.contains(&change.voter_pubkey) {
+                self.supermajority_stake = self
+                    .supermajority_stake
+                    .saturating_sub(change.old_stake)
+                    .saturating_add(change.new_stake);
+                self.min_member_stake = self.min_member_stake.min(change.new_stake);
+            } else {
+                self.max_other_stake = self.max_other_stake.max(change.new_stake);
+            }
+        }
+        let total_stake = u128::from(self.total_stake);
+        let supermajority_stake = u128::from(self.supermajority_stake);
+        self.min_member_stake > self.max_other_stake
+            && supermajority_stake * 3 > total_stake * 2
+            && (supermajority_stake - u128::from(self.min_member_stake)) * 3 <= total_stake * 2
+    }
+
+    /// Recomputes the supermajority set from the given stakes, returning how
+    /// it differs from the one previously recorded, if at all.
+    fn update(&mut self, stakes: &Stakes<StakeAccount>) -> Option<SupermajorityChange> {
+        let voters = stakes.supermajority_set();
+        self.total_stake = 0;
+        self.supermajority_stake = 0;
+        self.min_member_stake = u64::MAX;
+        self.max_other_stake = 0;
+        for (vote_pubkey, stake) in stakes.vote_accounts.delegated_stakes() {
+            self.total_stake += stake;
+            if voters.contains(vote_pubkey) {
+                self.supermajority_stake += stake;
+                self.min_member_stake = self.min_member_stake.min(stake);
+            } else {
+                self.max_other_stake = self.max_other_stake.max(stake);
+            }
+        }
+        if voters == self.voters {
+            return None;
+        }
+        let change = SupermajorityChange {
+            added: voters.difference(&self.voters).copied().sorted().collect(),
+            removed: self.voters.difference(&voters).copied().sorted().collect(),
+        };
+        self.voters = voters;
+        Some(change)
+    }
+}
+
+impl std::fmt::Debug for SupermajorityObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SupermajorityObserver")
+            .field("voters", &self.voters)
+            .field("total_stake", &self.total_stake)
+            .field("supermajority_stake", &self.supermajority_stake)
+            .field("min_member_stake", &self.min_member_stake)
+            .field("max_other_stake", &self.max_other_stake)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "frozen-abi")]
+impl solana_frozen_abi::abi_example::AbiExample for SupermajorityObserver {
+    fn example() -> Self {
+        Self {
+            callback: Arc::new(|_: &SupermajorityChange| {}),
+            voters: HashSet::default(),
+            total_stake: 0,
+            supermajority_stake: 0,
+            min_member_stake: 0,
+            max_other_stake: 0,
+        }
+    }
+}
+
+/// Callback registered through StakesCache::observe_total_stake_change,
+/// along with the total delegated stake it was last notified of.
+#[derive(Clone)]
+pub struct TotalStakeObserver {
+    callback: Arc<dyn Fn(/*old:*/ u64, /*new:*/ u64) + Send + Sync>,
+    threshold: f64,
+    total_stake: u64,
+    /// total delegated stake as of the last update.
+    current_total_stake: u64,
+}
+
+impl TotalStakeObserver {
+    /// Returns the total stake after applying the given stake changes to the
+    /// total stake as of the last update.
+    fn total_stake_with(&self, stake_changes: &[VoterStakeChange]) -> u64 {
+        stake_changes
+            .iter()
+            .fold(self.current_total_stake, |total_stake, change| {
+                total_stake
+                    .saturating_sub(change.old_stake)
+                    .saturating_add(change.new_stake)
+            })
+    }
+
+    /// Records the given total stake if it moved by more than the threshold,
+    /// returning the total stake previously recorded.
+    fn update(&mut self, total_stake: u64) -> Option<u64> {
+        self.current_total_stake = total_stake;
+        let change = total_stake.abs_diff(self.total_stake);
+        if change as f64 <= self.total_stake as f64 * self.threshold {
+            return None;
+        }
+        Some(std::mem::replace(&mut self.total_stake, total_stake))
+    }
+}
+
+impl std::fmt::Debug for TotalStakeObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TotalStakeObserver")
+            .field("threshold", &self.threshold)
+            .field("total_stake", &self.total_stake)
+            .field("current_total_stake", &self.current_total_stake)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "frozen-abi")]
+impl solana_frozen_abi::abi_example::AbiExample for TotalStakeObserver {
+    fn example() -> Self {
+        Self {
+            callback: Arc::new(|_, _| {}),
+            threshold: 0.0,
+            total_stake: 0,
+            current_total_stake: 0,
+        }
+    }
+}
+
+// Only the state which is part of the snapshot format is compared, so that
+// stakes compare equal across snapshot round trips, e.g. when comparing banks.
+impl<T: Clone + PartialEq> PartialEq for Stakes<T> {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            vote_accounts,
+            stake_delegations,
+            unused,
+            epoch,
+            stake_history,
+            vote_account_slots: _,
+            idle_epochs: _,
+        } = self;
+        vote_accounts == &other.vote_accounts
+            && stake_delegations == &other.stake_delegations
+            && unused == &other.unused
+            && epoch == &other.epoch
+            && stake_history == &other.stake_history
+    }
 }
 
 // For backward compatibility, we can only serialize and deserialize
@@ -231,6 +995,27 @@ pub enum StakesEnum {
     Stakes(Stakes<Stake>),
 }
 
+/// Number of cached stake accounts in each StakeStateV2 variant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StakeStateCounts {
+    pub uninitialized: usize,
+    pub initialized: usize,
+    pub stake: usize,
+    pub rewards_pool: usize,
+}
+
+/// The raw parts of a [`Stakes<StakeAccount>`], e.g. for custom serialization.
+/// See [`Stakes::into_parts`].
+#[derive(Debug)]
+pub struct StakesParts {
+    /// vote accounts and their delegated stake
+    pub vote_accounts: Arc<VoteAccountsHashMap>,
+    pub stake_delegations: ImHashMap<Pubkey, StakeAccount>,
+    pub unused: u64,
+    pub epoch: Epoch,
+    pub stake_history: StakeHistory,
+}
+
 impl<T: Clone> Stakes<T> {
     pub fn vote_accounts(&self) -> &VoteAccounts {
         &self.vote_accounts
@@ -239,31 +1024,554 @@ impl<T: Clone> Stakes<T> {
     pub(crate) fn staked_nodes(&self) -> Arc<HashMap<Pubkey, u64>> {
         self.vote_accounts.staked_nodes()
     }
-}
-
-impl Stakes<StakeAccount> {
-    /// Creates a Stake<StakeAccount> from Stake<Delegation> by loading the
-    /// full account state for respective stake pubkeys. get_account function
-    /// should return the account at the respective slot where stakes where
-    /// cached.
-    pub(crate) fn new<F>(stakes: &Stakes<Delegation>, get_account: F) -> Result<Self, Error>
-    where
-        F: Fn(&Pubkey) -> Option<AccountSharedData> + Sync,
-    {
-        let stake_delegations = stakes
-            .stake_delegations
-            .iter()
-            // im::HashMap doesn't support rayon so we manually build a temporary vector. Note this is
-            // what std HashMap::par_iter() does internally too.
-            .collect::<Vec<_>>()
-            .into_par_iter()
-            // We use fold/reduce to aggregate the results, which does a bit more work than calling
-            // collect()/collect_vec_list() and then im::HashMap::from_iter(collected.into_iter()),
-            // but it does it in background threads, so effectively it's faster.
-            .try_fold(ImHashMap::new, |mut map, (pubkey, delegation)| {
-                let Some(stake_account) = get_account(pubkey) else {
-                    return Err(Error::StakeAccountNotFound(*pubkey));
-                };
+
+    /// Returns the fraction of the total delegated stake held by the k
+    /// highest staked vote accounts, or 0.0 if nothing is staked.
+    pub fn top_k_share(&self, k: usize) -> f64 {
+        let mut stakes: Vec<u64> = self
+            .vote_accounts
+            .delegated_stakes()
+            .map(|(_vote_pubkey, stake)| stake)
+            .collect();
+        let total_stake: u64 = stakes.iter().sum();
+        if total_stake == 0 || k == 0 {
+            return 0.0;
+        }
+        if k < stakes.len() {
+            // Move the k largest stakes to the front without a full sort.
+            stakes.select_nth_unstable_by(k - 1, |a, b| b.cmp(a));
+            stakes.truncate(k);
+        }
+        let top_k_stake: u64 = stakes.iter().sum();
+        (top_k_stake as f64 / total_stake as f64).min(1.0)
+    }
+
+    /// Returns the node identity of the given vote account, or None if the
+    /// vote account is not cached. Only vote accounts with a parsable vote
+    /// state are ever cached.
+    pub fn node_identity(&self, voter: &Pubkey) -> Option<Pubkey> {
+        self.vote_accounts
+            .get(voter)
+            .map(|vote_account| *vote_account.node_pubkey())
+    }
+
+    /// Returns the minimum and maximum delegated stake across the voters
+    /// with nonzero stake, or None if no voter is staked.
+    pub fn stake_extremes(&self) -> Option<(u64, u64)> {
+        self.vote_accounts
+            .delegated_stakes()
+            .map(|(_, stake)| stake)
+            .filter(|&stake| stake != 0)
+            .minmax()
+            .into_option()
+    }
+
+    /// Sum of the delegated stake of all the cached vote accounts.
+    pub fn total_stake(&self) -> u64 {
+        self.vote_accounts
+            .delegated_stakes()
+            .map(|(_, stake)| stake)
+            .sum()
+    }
+
+    /// Returns the voters whose delegated stake is within [min, max], both
+    /// inclusive, sorted by stake in descending order.
+    pub fn voters_in_stake_range(&self, min: u64, max: u64) -> Vec<(Pubkey, u64)> {
+        self.vote_accounts
+            .delegated_stakes()
+            .filter(|(_, stake)| (min..=max).contains(stake))
+            .map(|(vote_pubkey, stake)| (*vote_pubkey, stake))
+            .sorted_unstable_by_key(|&(vote_pubkey, stake)| (std::cmp::Reverse(stake), vote_pubkey))
+            .collect()
+    }
+
+    /// Returns the cached vote accounts whose authorized withdrawer is the
+    /// given pubkey. This scans all the vote accounts.
+    pub fn vote_accounts_by_authorized_withdrawer(&self, withdrawer: &Pubkey) -> Vec<Pubkey> {
+        self.vote_accounts
+            .iter()
+            .filter(|(_, vote_account)| {
+                &vote_account.vote_state().authorized_withdrawer == withdrawer
+            })
+            .map(|(vote_pubkey, _)| *vote_pubkey)
+            .collect()
+    }
+
+    /// Samples k distinct staked voters without replacement, where each voter
+    /// is selected with probability proportional to its delegated stake
+    /// (weighted reservoir sampling, A-Res). Returns all the staked voters if
+    /// there are no more than k of them.
+    pub fn weighted_subset<R: Rng>(&self, k: usize, rng: &mut R) -> Vec<Pubkey> {
+        let mut voters: Vec<_> = self
+            .vote_accounts
+            .delegated_stakes()
+            .filter(|(_, stake)| *stake != 0)
+            .map(|(vote_pubkey, stake)| (*vote_pubkey, stake))
+            .collect();
+        // Iterate in a deterministic order so that results are reproducible
+        // given the same rng.
+        voters.sort_unstable();
+        // Equivalent to ranking by u^(1/stake) but without losing precision
+        // for large stakes.
+        let mut voters: Vec<_> = voters
+            .into_iter()
+            .map(|(vote_pubkey, stake)| (rng.gen::<f64>().ln() / stake as f64, vote_pubkey))
+            .collect();
+        if k < voters.len() {
+            voters.select_nth_unstable_by(k, |a, b| b.0.total_cmp(&a.0));
+            voters.truncate(k);
+        }
+        voters
+            .into_iter()
+            .map(|(_, vote_pubkey)| vote_pubkey)
+            .collect()
+    }
+
+    /// Returns the voters which are staked, i.e. have non-zero delegated
+    /// stake, in self but not in previous, and those which are staked in
+    /// previous but not in self, both sorted by pubkey.
+    pub fn voter_churn(&self, previous: &Self) -> (Vec<Pubkey>, Vec<Pubkey>) {
+        let staked_voters = |stakes: &Self| -> HashSet<Pubkey> {
+            stakes
+                .vote_accounts
+                .delegated_stakes()
+                .filter(|(_, stake)| *stake != 0)
+                .map(|(vote_pubkey, _)| *vote_pubkey)
+                .collect()
+        };
+        let voters = staked_voters(self);
+        let previous_voters = staked_voters(previous);
+        let newly_staked = voters
+            .difference(&previous_voters)
+            .copied()
+            .sorted_unstable();
+        let no_longer_staked = previous_voters
+            .difference(&voters)
+            .copied()
+            .sorted_unstable();
+        (newly_staked.collect(), no_longer_staked.collect())
+    }
+
+    /// Returns the smallest set of voters which together hold more than 2/3
+    /// of the total delegated stake, taking voters in descending order of
+    /// stake, ties broken by pubkey. Empty if there is no delegated stake.
+    pub fn supermajority_set(&self) -> HashSet<Pubkey> {
+        let total_stake = u128::from(self.total_stake());
+        let mut supermajority_stake = 0u128;
+        let mut voters = HashSet::new();
+        let staked_voters = self
+            .vote_accounts
+            .delegated_stakes()
+            .filter(|(_, stake)| *stake != 0)
+            .sorted_unstable_by_key(|&(vote_pubkey, stake)| {
+                (std::cmp::Reverse(stake), vote_pubkey)
+            });
+        for (vote_pubkey, stake) in staked_voters {
+            if supermajority_stake * 3 > total_stake * 2 {
+                break;
+            }
+            supermajority_stake += u128::from(stake);
+            voters.insert(*vote_pubkey);
+        }
+        voters
+    }
+
+    /// Estimates the voter's share of the given total epoch rewards, in
+    /// proportion to its delegated stake. Returns zero if there is no
+    /// delegated stake.
+    pub fn estimate_reward_share(&self, voter: &Pubkey, total_epoch_rewards: u64) -> u64 {
+        let total_stake = self.total_stake();
+        if total_stake == 0 {
+            return 0;
+        }
+        let stake = self.vote_accounts.get_delegated_stake(voter);
+        let reward_share =
+            u128::from(total_epoch_rewards) * u128::from(stake) / u128::from(total_stake);
+        // Never exceeds total_epoch_rewards since stake <= total_stake.
+        reward_share as u64
+    }
+
+    /// Iterates over the cached vote accounts, along with their delegated
+    /// stake, in arbitrary order.
+    pub fn iter_vote_accounts(&self) -> impl Iterator<Item = (Pubkey, u64, &VoteAccount)> {
+        self.vote_accounts
+            .as_ref()
+            .iter()
+            .map(|(vote_pubkey, (stake, vote_account))| (*vote_pubkey, *stake, vote_account))
+    }
+
+    /// Same as iter_vote_accounts, but yields the vote states of the vote
+    /// accounts. Vote accounts are deserialized once when cached, so unlike
+    /// parsing the account data, this never fails nor allocates.
+    pub fn iter_vote_states(&self) -> impl Iterator<Item = (Pubkey, u64, &VoteState)> {
+        self.iter_vote_accounts()
+            .map(|(vote_pubkey, stake, vote_account)| {
+                (vote_pubkey, stake, vote_account.vote_state())
+            })
+    }
+
+    /// Releases unused capacity of the vote accounts map, e.g. after evicting
+    /// or removing many vote accounts. The stake delegations are kept in
+    /// persistent maps which do not retain capacity beyond their contents.
+    /// Nothing is released while the vote accounts are shared with clones of
+    /// these stakes, see VoteAccounts::shrink_to_fit.
+    pub fn compact(&mut self) {
+        self.vote_accounts.shrink_to_fit();
+    }
+
+    /// Returns the cached vote accounts, sorted by pubkey, whose authorized
+    /// voter at the given epoch is the given pubkey.
+    pub fn vote_accounts_by_authorized_voter(
+        &self,
+        authority: &Pubkey,
+        epoch: Epoch,
+    ) -> Vec<Pubkey> {
+        self.vote_accounts
+            .iter()
+            .filter(|(_, vote_account)| {
+                vote_account.vote_state().get_authorized_voter(epoch) == Some(*authority)
+            })
+            .map(|(vote_pubkey, _)| *vote_pubkey)
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Sets the delegated stake of the voter directly, caching a placeholder
+    /// vote account for it if it is not cached yet, without any stake
+    /// delegations backing the stake. Only meant to quickly set up stake
+    /// distributions in tests and simulations.
+    #[cfg(feature = "dev-context-only-utils")]
+    pub fn force_set_stake(&mut self, voter: &Pubkey, stake: u64) {
+        let current_stake = self.vote_accounts.get_delegated_stake(voter);
+        if self.vote_accounts.get(voter).is_none() {
+            let account = solana_vote_program::vote_state::create_account(voter, voter, 0, 1);
+            let vote_account = VoteAccount::try_from(account).unwrap();
+            self.vote_accounts.insert(*voter, vote_account, || stake);
+        } else if stake > current_stake {
+            self.vote_accounts.add_stake(voter, stake - current_stake);
+        } else {
+            self.vote_accounts.sub_stake(voter, current_stake - stake);
+        }
+    }
+
+    /// Returns the total delegated stake of the vote accounts not in the
+    /// given set, e.g. to model the given voters going offline.
+    pub fn total_stake_excluding(&self, exclude: &HashSet<Pubkey>) -> u64 {
+        self.vote_accounts
+            .delegated_stakes()
+            .filter(|(vote_pubkey, _)| !exclude.contains(*vote_pubkey))
+            .map(|(_, stake)| stake)
+            .sum()
+    }
+
+    /// Returns the number of vote accounts whose delegated stake falls in
+    /// each of the buckets defined by the given ascending bucket boundaries:
+    /// bucket i counts the stakes in [boundaries[i - 1], boundaries[i]),
+    /// with the first and last buckets unbounded below and above. There is
+    /// one more bucket than boundaries. Vote accounts without delegated
+    /// stake are only counted if include_unstaked is true.
+    pub fn voter_stake_histogram(
+        &self,
+        bucket_boundaries: &[u64],
+        include_unstaked: bool,
+    ) -> Vec<usize> {
+        debug_assert!(bucket_boundaries.windows(2).all(|w| w[0] < w[1]));
+        let mut histogram = vec![0; bucket_boundaries.len() + 1];
+        for (_, stake) in self.vote_accounts.delegated_stakes() {
+            if stake != 0 || include_unstaked {
+                histogram[bucket_boundaries.partition_point(|&boundary| boundary <= stake)] += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Returns the delegated stake and a copy of the cached vote account, if
+    /// any, which callers may freely modify, e.g. in simulations.
+    pub fn vote_account_cloned(&self, voter: &Pubkey) -> Option<(u64, AccountSharedData)> {
+        let (stake, vote_account) = self.vote_accounts.as_ref().get(voter)?;
+        Some((*stake, AccountSharedData::from(vote_account.clone())))
+    }
+
+    /// Returns the population standard deviation of the non-zero delegated
+    /// stakes of the vote accounts, computed in a single pass with Welford's
+    /// algorithm, or 0.0 if fewer than two vote accounts are staked.
+    pub fn stake_stddev(&self) -> f64 {
+        let (count, _mean, sum_squares) = self
+            .vote_accounts
+            .delegated_stakes()
+            .filter(|(_, stake)| *stake != 0)
+            .fold(
+                (0u64, 0f64, 0f64),
+                |(count, mean, sum_squares), (_, stake)| {
+                    let stake = stake as f64;
+                    let count = count + 1;
+                    let delta = stake - mean;
+                    let mean = mean + delta / count as f64;
+                    (count, mean, sum_squares + delta * (stake - mean))
+                },
+            );
+        if count < 2 {
+            return 0.0;
+        }
+        (sum_squares / count as f64).sqrt()
+    }
+
+    /// Same as comparing for equality, but ignores the epoch and the stake
+    /// history, so that only the cached vote accounts, along with their
+    /// delegated stake, and the stake delegations are compared.
+    pub fn equal_ignoring_epoch(&self, other: &Self) -> bool
+    where
+        T: PartialEq,
+    {
+        self.vote_accounts == other.vote_accounts
+            && self.stake_delegations == other.stake_delegations
+    }
+
+    /// Writes the staked vote accounts, sorted by pubkey, as a JSON array of
+    /// `{"voter", "stake", "node_identity", "commission"}` objects, one vote
+    /// account at a time.
+    pub fn write_vote_accounts_json<W: std::io::Write>(
+        &self,
+        mut writer: W,
+    ) -> std::io::Result<()> {
+        #[derive(Serialize)]
+        struct VoteAccountJson {
+            voter: String,
+            stake: u64,
+            node_identity: String,
+            commission: u8,
+        }
+        let vote_accounts = self
+            .vote_accounts
+            .as_ref()
+            .iter()
+            .filter(|(_, (stake, _))| *stake != 0)
+            .sorted_unstable_by_key(|(vote_pubkey, _)| *vote_pubkey);
+        writer.write_all(b"[")?;
+        for (i, (vote_pubkey, (stake, vote_account))) in vote_accounts.enumerate() {
+            if i != 0 {
+                writer.write_all(b",")?;
+            }
+            let vote_state = vote_account.vote_state();
+            let vote_account = VoteAccountJson {
+                voter: vote_pubkey.to_string(),
+                stake: *stake,
+                node_identity: vote_state.node_pubkey.to_string(),
+                commission: vote_state.commission,
+            };
+            serde_json::to_writer(&mut writer, &vote_account)?;
+        }
+        writer.write_all(b"]")
+    }
+
+    /// Returns the average commission of the vote accounts weighted by their
+    /// delegated stake, or 0.0 if there is no delegated stake.
+    pub fn stake_weighted_mean_commission(&self) -> f64 {
+        let (total_stake, weighted_commission) = self.vote_accounts.as_ref().values().fold(
+            (0u128, 0u128),
+            |(total_stake, weighted_commission), (stake, vote_account)| {
+                let stake = u128::from(*stake);
+                let commission = u128::from(vote_account.vote_state().commission);
+                (
+                    total_stake + stake,
+                    weighted_commission + stake * commission,
+                )
+            },
+        );
+        if total_stake == 0 {
+            return 0.0;
+        }
+        weighted_commission as f64 / total_stake as f64
+    }
+
+    /// Returns the delegated stake of each vote account scaled by the given
+    /// weight, e.g. to weight stake by validator performance. The cached
+    /// stakes are not modified.
+    pub fn weighted_stake(&self, weight: impl Fn(&Pubkey) -> f64) -> HashMap<Pubkey, f64> {
+        self.vote_accounts
+            .delegated_stakes()
+            .map(|(vote_pubkey, stake)| (*vote_pubkey, stake as f64 * weight(vote_pubkey)))
+            .collect()
+    }
+
+    /// Returns the points of the Lorenz curve of the delegated stakes of the
+    /// vote accounts: taking vote accounts in ascending order of stake, the
+    /// cumulative fraction of vote accounts against the cumulative fraction
+    /// of the total delegated stake, starting at (0.0, 0.0) and ending at
+    /// (1.0, 1.0). Empty if there is no delegated stake.
+    pub fn lorenz_points(&self) -> Vec<(f64, f64)> {
+        let stakes: Vec<u64> = self
+            .vote_accounts
+            .delegated_stakes()
+            .map(|(_, stake)| stake)
+            .sorted_unstable()
+            .collect();
+        let total_stake: u128 = stakes.iter().map(|&stake| u128::from(stake)).sum();
+        if total_stake == 0 {
+            return Vec::default();
+        }
+        let num_voters = stakes.len() as f64;
+        let points = stakes
+            .into_iter()
+            .scan(0u128, |cumulative_stake, stake| {
+                *cumulative_stake += u128::from(stake);
+                Some(*cumulative_stake)
+            })
+            .enumerate()
+            .map(|(index, cumulative_stake)| {
+                (
+                    (index + 1) as f64 / num_voters,
+                    cumulative_stake as f64 / total_stake as f64,
+                )
+            });
+        std::iter::once((0.0, 0.0)).chain(points).collect()
+    }
+
+    /// Returns the cached vote accounts without delegated stake, sorted by
+    /// pubkey, e.g. to alert on vote accounts at risk of being evicted, see
+    /// evict_idle_voters.
+    pub fn unstaked_voters(&self) -> Vec<Pubkey> {
+        self.vote_accounts
+            .delegated_stakes()
+            .filter(|(_, stake)| *stake == 0)
+            .map(|(vote_pubkey, _)| *vote_pubkey)
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Returns the delegated stake of each node, summed across the vote
+    /// accounts with that node identity, along with the vote state of the
+    /// node's vote account with the most stake, ties broken by the smallest
+    /// vote account pubkey. Vote accounts without delegated stake are
+    /// excluded.
+    pub fn epoch_vote_accounts(&self) -> HashMap</*node:*/ Pubkey, (u64, VoteState)> {
+        let mut epoch_vote_accounts = HashMap::<Pubkey, (u64, VoteState)>::new();
+        let vote_accounts = self
+            .iter_vote_states()
+            .filter(|(_, stake, _)| *stake != 0)
+            .sorted_unstable_by_key(|&(vote_pubkey, stake, _)| {
+                (std::cmp::Reverse(stake), vote_pubkey)
+            });
+        for (_, stake, vote_state) in vote_accounts {
+            epoch_vote_accounts
+                .entry(vote_state.node_pubkey)
+                .and_modify(|(node_stake, _)| *node_stake += stake)
+                .or_insert_with(|| (stake, vote_state.clone()));
+        }
+        epoch_vote_accounts
+    }
+
+    /// Returns the number of vote accounts whose delegated stake is at least
+    /// threshold lamports, along with their total delegated stake.
+    pub fn stake_above(&self, threshold: u64) -> (usize, u64) {
+        self.vote_accounts
+            .delegated_stakes()
+            .filter(|(_, stake)| *stake >= threshold)
+            .fold((0, 0), |(count, total_stake), (_, stake)| {
+                (count + 1, total_stake + stake)
+            })
+    }
+
+    /// Returns the SHA-256 digest of the delegated stake of each vote account,
+    /// hashed in order of vote account pubkey. Unlike checksum, the digest is
+    /// stable across processes, so nodes with the same stakes derive the same
+    /// digest, e.g. to seed a random number generator.
+    pub fn stake_digest(&self) -> [u8; 32] {
+        let mut hasher = solana_sdk::hash::Hasher::default();
+        for (vote_pubkey, stake) in self.vote_accounts.delegated_stakes().sorted_unstable() {
+            hasher.hash(vote_pubkey.as_ref());
+            hasher.hash(&stake.to_le_bytes());
+        }
+        hasher.result().to_bytes()
+    }
+
+    /// Returns the change of the delegated stake of each vote account from
+    /// previous to self. Vote accounts cached in only one of them are
+    /// treated as having no delegated stake in the other.
+    pub fn stake_trends(&self, previous: &Self) -> HashMap<Pubkey, StakeTrend> {
+        let vote_pubkeys: HashSet<&Pubkey> = self
+            .vote_accounts
+            .iter()
+            .chain(previous.vote_accounts.iter())
+            .map(|(vote_pubkey, _)| vote_pubkey)
+            .collect();
+        vote_pubkeys
+            .into_iter()
+            .map(|vote_pubkey| {
+                let stake = self.vote_accounts.get_delegated_stake(vote_pubkey);
+                let previous_stake = previous.vote_accounts.get_delegated_stake(vote_pubkey);
+                let trend = match stake.cmp(&previous_stake) {
+                    std::cmp::Ordering::Greater => StakeTrend::Up(stake - previous_stake),
+                    std::cmp::Ordering::Less => StakeTrend::Down(previous_stake - stake),
+                    std::cmp::Ordering::Equal => StakeTrend::Flat,
+                };
+                (*vote_pubkey, trend)
+            })
+            .collect()
+    }
+
+    /// Returns the total delegated stake of the vote accounts other than the
+    /// rank vote accounts with the most stake, e.g. to size the long tail of
+    /// small validators. Zero if rank is at least the number of vote
+    /// accounts.
+    pub fn tail_stake(&self, rank: usize) -> u64 {
+        self.vote_accounts
+            .delegated_stakes()
+            .map(|(_, stake)| stake)
+            .sorted_unstable_by_key(|&stake| std::cmp::Reverse(stake))
+            .skip(rank)
+            .sum()
+    }
+
+    /// Returns the staked nodes, see staked_nodes, along with their delegated
+    /// stake, sorted by stake in descending order, ties broken by pubkey.
+    /// Nodes without delegated stake are excluded.
+    pub fn staked_node_pubkeys_sorted(&self) -> Vec<(Pubkey, u64)> {
+        self.staked_nodes()
+            .iter()
+            .filter(|(_, stake)| **stake != 0)
+            .map(|(node_pubkey, stake)| (*node_pubkey, *stake))
+            .sorted_unstable_by_key(|&(node_pubkey, stake)| (std::cmp::Reverse(stake), node_pubkey))
+            .collect()
+    }
+
+    /// Returns the delegated stake of each vote account net of its
+    /// commission, i.e. scaled by (100 - commission) / 100 and rounded down,
+    /// approximating the stake whose rewards accrue to the delegators.
+    pub fn post_commission_stake(&self) -> HashMap<Pubkey, u64> {
+        self.iter_vote_states()
+            .map(|(vote_pubkey, stake, vote_state)| {
+                let commission = u128::from(vote_state.commission.min(100));
+                let stake = u128::from(stake) * (100 - commission) / 100;
+                (vote_pubkey, stake as u64)
+            })
+            .collect()
+    }
+}
+
+impl Stakes<StakeAccount> {
+    /// Creates a Stake<StakeAccount> from Stake<Delegation> by loading the
+    /// full account state for respective stake pubkeys. get_account function
+    /// should return the account at the respective slot where stakes where
+    /// cached.
+    pub(crate) fn new<F>(stakes: &Stakes<Delegation>, get_account: F) -> Result<Self, Error>
+    where
+        F: Fn(&Pubkey) -> Option<AccountSharedData> + Sync,
+    {
+        let stake_delegations = stakes
+            .stake_delegations
+            .iter()
+            // im::HashMap doesn't support rayon so we manually build a temporary vector. Note this is
+            // what std HashMap::par_iter() does internally too.
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            // We use fold/reduce to aggregate the results, which does a bit more work than calling
+            // collect()/collect_vec_list() and then im::HashMap::from_iter(collected.into_iter()),
+            // but it does it in background threads, so effectively it's faster.
+            .try_fold(ImHashMap::new, |mut map, (pubkey, delegation)| {
+                let Some(stake_account) = get_account(pubkey) else {
+                    return Err(Error::StakeAccountNotFound(*pubkey));
+                };
 
                 // Assert that all valid vote-accounts referenced in stake delegations are already
                 // contained in `stakes.vote_account`.
@@ -312,6 +1620,8 @@ impl Stakes<StakeAccount> {
             unused: stakes.unused,
             epoch: stakes.epoch,
             stake_history: stakes.stake_history.clone(),
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
         })
     }
 
@@ -327,103 +1637,573 @@ impl Stakes<StakeAccount> {
             unused: 0,
             epoch,
             stake_history: StakeHistory::default(),
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
         }
     }
 
-    pub(crate) fn history(&self) -> &StakeHistory {
-        &self.stake_history
-    }
-
-    fn activate_epoch(
-        &mut self,
-        next_epoch: Epoch,
+    /// Creates stakes from the given vote accounts, along with their
+    /// delegated stake, and stake delegations, verifying that the delegated
+    /// stake of each vote account matches the stake delegations. Delegated
+    /// stakes are recalculated in parallel on the given thread pool.
+    pub fn from_parts_validated(
+        epoch: Epoch,
+        stake_history: StakeHistory,
+        vote_accounts: VoteAccounts,
+        stake_delegations: ImHashMap<Pubkey, StakeAccount>,
         thread_pool: &ThreadPool,
         new_rate_activation_epoch: Option<Epoch>,
-    ) {
-        let stake_delegations: Vec<_> = self.stake_delegations.values().collect();
-        // Wrap up the prev epoch by adding new stake history entry for the
-        // prev epoch.
-        let stake_history_entry = thread_pool.install(|| {
-            stake_delegations
-                .par_iter()
-                .fold(StakeActivationStatus::default, |acc, stake_account| {
-                    let delegation = stake_account.delegation();
-                    acc + delegation.stake_activating_and_deactivating(
-                        self.epoch,
-                        &self.stake_history,
-                        new_rate_activation_epoch,
-                    )
-                })
-                .reduce(StakeActivationStatus::default, Add::add)
-        });
-        self.stake_history.add(self.epoch, stake_history_entry);
-        self.epoch = next_epoch;
-        // Refresh the stake distribution of vote accounts for the next epoch,
-        // using new stake history.
-        self.vote_accounts = refresh_vote_accounts(
+    ) -> Result<Self, Error> {
+        let delegations: Vec<_> = stake_delegations.values().collect();
+        let recalculated_vote_accounts = refresh_vote_accounts(
             thread_pool,
-            self.epoch,
-            &self.vote_accounts,
-            &stake_delegations,
-            &self.stake_history,
+            epoch,
+            &vote_accounts,
+            &delegations,
+            &stake_history,
             new_rate_activation_epoch,
         );
+        for (vote_pubkey, stake) in recalculated_vote_accounts.delegated_stakes() {
+            if vote_accounts.get_delegated_stake(vote_pubkey) != stake {
+                return Err(Error::VoteAccountStakeMismatch(*vote_pubkey));
+            }
+        }
+        Ok(Self {
+            vote_accounts,
+            stake_delegations,
+            unused: 0,
+            epoch,
+            stake_history,
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
+        })
     }
 
-    /// Sum the stakes that point to the given voter_pubkey
-    fn calculate_stake(
-        stake_delegations: &ImHashMap<Pubkey, StakeAccount>,
-        voter_pubkey: &Pubkey,
-        epoch: Epoch,
-        stake_history: &StakeHistory,
+    /// Combines partial stakes built independently from disjoint shards of
+    /// accounts, e.g. when scanning accounts in parallel. All partials are
+    /// expected to be at the same epoch and stake history. Since a voter's
+    /// stake accounts may have landed in different shards than its vote
+    /// account, delegated stakes are summed across all the shards.
+    pub fn concat(
+        partials: Vec<Self>,
+        thread_pool: &ThreadPool,
         new_rate_activation_epoch: Option<Epoch>,
-    ) -> u64 {
-        stake_delegations
-            .values()
-            .map(StakeAccount::delegation)
-            .filter(|delegation| &delegation.voter_pubkey == voter_pubkey)
-            .map(|delegation| delegation.stake(epoch, stake_history, new_rate_activation_epoch))
-            .sum()
+    ) -> Self {
+        let Some(first) = partials.first() else {
+            return Self::default();
+        };
+        let unused = first.unused;
+        let epoch = first.epoch;
+        let stake_history = first.stake_history.clone();
+        let mut vote_accounts = HashMap::new();
+        let mut stake_delegations = ImHashMap::new();
+        for partial in partials {
+            debug_assert_eq!(partial.epoch, epoch);
+            vote_accounts.extend(
+                partial
+                    .vote_accounts
+                    .iter()
+                    .map(|(pubkey, vote_account)| (*pubkey, (0, vote_account.clone()))),
+            );
+            stake_delegations = stake_delegations.union(partial.stake_delegations);
+        }
+        let mut stakes = Self {
+            vote_accounts: VoteAccounts::from(Arc::new(vote_accounts)),
+            stake_delegations,
+            unused,
+            epoch,
+            stake_history,
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
+        };
+        stakes.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+        stakes
     }
 
-    /// Sum the lamports of the vote accounts and the delegated stake
-    pub(crate) fn vote_balance_and_staked(&self) -> u64 {
-        let get_stake = |stake_account: &StakeAccount| stake_account.delegation().stake;
-        let get_lamports = |(_, vote_account): (_, &VoteAccount)| vote_account.lamports();
+    /// Same as StakesCache::check_and_store, but updates these stakes
+    /// directly. Accounts which are neither vote nor stake accounts are
+    /// ignored.
+    pub fn check_and_store(
+        &mut self,
+        pubkey: &Pubkey,
+        account: &impl ReadableAccount,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        if let Some(update) = StakesCacheUpdate::new(account) {
+            self.apply_update(pubkey, update, new_rate_activation_epoch);
+        }
+    }
 
-        self.stake_delegations.values().map(get_stake).sum::<u64>()
-            + self.vote_accounts.iter().map(get_lamports).sum::<u64>()
+    /// Re-stores the accounts for the given pubkeys as returned by
+    /// get_account, e.g. to refresh cached accounts whose lamports changed.
+    /// Pubkeys for which get_account returns None are removed from the cache.
+    pub fn rehydrate_accounts<F>(
+        &mut self,
+        pubkeys: &[Pubkey],
+        get_account: F,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) where
+        F: Fn(&Pubkey) -> Option<AccountSharedData>,
+    {
+        for pubkey in pubkeys {
+            let account = get_account(pubkey);
+            self.store_or_remove(pubkey, account.as_ref(), new_rate_activation_epoch);
+        }
     }
 
-    fn remove_vote_account(&mut self, vote_pubkey: &Pubkey) -> Option<VoteAccount> {
-        self.vote_accounts.remove(vote_pubkey).map(|(_, a)| a)
+    /// Returns the account-level changes which transform base into these
+    /// stakes, where None means the pubkey is removed from the cache.
+    /// Removals come first, so the patch can be applied in order with
+    /// apply_patch. Both stakes are assumed to be at the same epoch and
+    /// stake history.
+    pub fn patch_from(&self, base: &Self) -> Vec<(Pubkey, Option<AccountSharedData>)> {
+        let removed_vote_accounts = base
+            .vote_accounts
+            .iter()
+            .map(|(vote_pubkey, _)| vote_pubkey)
+            .filter(|vote_pubkey| self.vote_accounts.get(vote_pubkey).is_none());
+        let removed_stake_delegations = base
+            .stake_delegations
+            .keys()
+            .filter(|stake_pubkey| !self.stake_delegations.contains_key(*stake_pubkey));
+        let removals = removed_vote_accounts
+            .chain(removed_stake_delegations)
+            .unique()
+            .map(|pubkey| (*pubkey, None));
+        let vote_accounts = self
+            .vote_accounts
+            .iter()
+            .filter(|(vote_pubkey, vote_account)| {
+                base.vote_accounts.get(vote_pubkey) != Some(*vote_account)
+            })
+            .map(|(vote_pubkey, vote_account)| {
+                (*vote_pubkey, Some(vote_account.account().clone()))
+            });
+        let stake_delegations = self
+            .stake_delegations
+            .iter()
+            .filter(|(stake_pubkey, stake_account)| {
+                base.stake_delegations.get(*stake_pubkey) != Some(*stake_account)
+            })
+            .map(|(stake_pubkey, stake_account)| {
+                (*stake_pubkey, Some(stake_account.account().clone()))
+            });
+        removals
+            .chain(vote_accounts)
+            .chain(stake_delegations)
+            .collect()
     }
 
-    fn remove_stake_delegation(
+    /// Applies, in order, a patch as returned by patch_from.
+    pub fn apply_patch(
         &mut self,
-        stake_pubkey: &Pubkey,
+        patch: Vec<(Pubkey, Option<AccountSharedData>)>,
         new_rate_activation_epoch: Option<Epoch>,
     ) {
-        if let Some(stake_account) = self.stake_delegations.remove(stake_pubkey) {
-            let removed_delegation = stake_account.delegation();
-            let removed_stake = removed_delegation.stake(
-                self.epoch,
-                &self.stake_history,
-                new_rate_activation_epoch,
-            );
-            self.vote_accounts
-                .sub_stake(&removed_delegation.voter_pubkey, removed_stake);
-        }
+        self.apply_changes(patch, new_rate_activation_epoch);
     }
 
-    fn upsert_vote_account(
-        &mut self,
+    /// Applies, in order, the given account changes, e.g. from an incremental
+    /// snapshot, where None means the pubkey is removed from the cache. The
+    /// result is the same as storing or removing each account in turn. Each
+    /// change updates the delegated stake of at most one vote account
+    /// incrementally, so no further reconciliation is needed at the end.
+    pub fn apply_changes<I>(&mut self, changes: I, new_rate_activation_epoch: Option<Epoch>)
+    where
+        I: IntoIterator<Item = (Pubkey, Option<AccountSharedData>)>,
+    {
+        for (pubkey, account) in changes {
+            self.store_or_remove(&pubkey, account.as_ref(), new_rate_activation_epoch);
+        }
+    }
+
+    fn store_or_remove(
+        &mut self,
+        pubkey: &Pubkey,
+        account: Option<&AccountSharedData>,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        match account {
+            Some(account) => self.check_and_store(pubkey, account, new_rate_activation_epoch),
+            None => {
+                self.remove_vote_account(pubkey);
+                self.remove_stake_delegation(pubkey, new_rate_activation_epoch);
+            }
+        }
+    }
+
+    /// Returns the voters whose delegated stake may be changed by applying
+    /// the given update, along with their current delegated stake.
+    fn touched_voter_stakes(
+        &self,
+        pubkey: &Pubkey,
+        update: &StakesCacheUpdate,
+    ) -> Vec<(/*voter:*/ Pubkey, /*stake:*/ u64)> {
+        let old_voter = || {
+            self.stake_delegations
+                .get(pubkey)
+                .map(|stake_account| stake_account.delegation().voter_pubkey)
+        };
+        let voters = match update {
+            StakesCacheUpdate::UpsertVoteAccount(_) | StakesCacheUpdate::RemoveVoteAccount => {
+                vec![*pubkey]
+            }
+            StakesCacheUpdate::UpsertStakeDelegation(stake_account) => {
+                let new_voter = stake_account.delegation().voter_pubkey;
+                old_voter()
+                    .into_iter()
+                    .filter(|old_voter| *old_voter != new_voter)
+                    .chain(std::iter::once(new_voter))
+                    .collect()
+            }
+            StakesCacheUpdate::UpsertUndelegatedStakeAccount(_)
+            | StakesCacheUpdate::RemoveStakeDelegation => old_voter().into_iter().collect(),
+        };
+        voters
+            .into_iter()
+            .map(|voter| (voter, self.vote_accounts.get_delegated_stake(&voter)))
+            .collect()
+    }
+
+    fn apply_update(
+        &mut self,
+        pubkey: &Pubkey,
+        update: StakesCacheUpdate,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Option<VoteAccount> {
+        match update {
+            StakesCacheUpdate::UpsertVoteAccount(vote_account) => {
+                self.vote_account_slots.remove(pubkey);
+                self.upsert_vote_account(pubkey, vote_account, new_rate_activation_epoch)
+            }
+            StakesCacheUpdate::RemoveVoteAccount => {
+                self.vote_account_slots.remove(pubkey);
+                self.remove_vote_account(pubkey)
+            }
+            StakesCacheUpdate::UpsertStakeDelegation(stake_account) => {
+                self.upsert_stake_delegation(*pubkey, stake_account, new_rate_activation_epoch);
+                None
+            }
+            // Undelegated stake accounts contribute no stake, see
+            // StakesCache::undelegated_stake_accounts.
+            StakesCacheUpdate::UpsertUndelegatedStakeAccount(_)
+            | StakesCacheUpdate::RemoveStakeDelegation => {
+                self.remove_stake_delegation(pubkey, new_rate_activation_epoch);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn history(&self) -> &StakeHistory {
+        &self.stake_history
+    }
+
+    fn activate_epoch(
+        &mut self,
+        next_epoch: Epoch,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        self.wrap_up_epoch(next_epoch, thread_pool, new_rate_activation_epoch);
+        // Refresh the stake distribution of vote accounts for the next epoch,
+        // using new stake history.
+        self.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+        self.update_idle_epochs();
+    }
+
+    /// Same as activating the next epoch, then updating the stake accounts
+    /// with the given rewards, and then pruning the stake delegations below
+    /// prune_below lamports (see prune_stake_delegations); but recalculates
+    /// the delegated stake of the vote accounts only once.
+    pub fn reconcile_epoch_boundary(
+        &mut self,
+        next_epoch: Epoch,
+        stake_rewards: &[StakeReward],
+        prune_below: Option<u64>,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        self.wrap_up_epoch(next_epoch, thread_pool, new_rate_activation_epoch);
+        self.merge_stake_rewards(thread_pool, stake_rewards, prune_below);
+        self.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+        self.update_idle_epochs();
+    }
+
+    /// Removes the stake delegations whose delegated stake is below min_stake
+    /// lamports from the cache, e.g. to prune dust.
+    pub fn prune_stake_delegations(
+        &mut self,
+        min_stake: u64,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        self.stake_delegations
+            .retain(|_, stake_account| stake_account.delegation().stake >= min_stake);
+        self.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+    }
+
+    /// Adds the stake history entry for the current epoch and advances to the
+    /// next epoch, without recalculating the delegated stake of the vote
+    /// accounts.
+    fn wrap_up_epoch(
+        &mut self,
+        next_epoch: Epoch,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        let stake_delegations: Vec<_> = self.stake_delegations.values().collect();
+        // Wrap up the prev epoch by adding new stake history entry for the
+        // prev epoch.
+        let stake_history_entry = thread_pool.install(|| {
+            stake_delegations
+                .par_iter()
+                .fold(StakeActivationStatus::default, |acc, stake_account| {
+                    let delegation = stake_account.delegation();
+                    acc + delegation.stake_activating_and_deactivating(
+                        self.epoch,
+                        &self.stake_history,
+                        new_rate_activation_epoch,
+                    )
+                })
+                .reduce(StakeActivationStatus::default, Add::add)
+        });
+        self.stake_history.add(self.epoch, stake_history_entry);
+        self.epoch = next_epoch;
+    }
+
+    fn recalculate_vote_account_stakes(
+        &mut self,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        let stake_delegations: Vec<_> = self.stake_delegations.values().collect();
+        self.vote_accounts = refresh_vote_accounts(
+            thread_pool,
+            self.epoch,
+            &self.vote_accounts,
+            &stake_delegations,
+            &self.stake_history,
+            new_rate_activation_epoch,
+        );
+    }
+
+    /// Counts one more idle epoch for each vote account without delegated
+    /// stake, dropping the counts of the vote accounts which are staked.
+    fn update_idle_epochs(&mut self) {
+        let idle_epochs = self
+            .vote_accounts
+            .as_ref()
+            .iter()
+            .filter(|(_, (stake, _))| *stake == 0)
+            .map(|(vote_pubkey, _)| {
+                let epochs = self
+                    .idle_epochs
+                    .get(vote_pubkey)
+                    .copied()
+                    .unwrap_or_default();
+                (*vote_pubkey, epochs.saturating_add(1))
+            })
+            .collect();
+        self.idle_epochs = idle_epochs;
+    }
+
+    /// Removes the vote accounts which have had no delegated stake for more
+    /// than max_idle_epochs consecutive epochs, as counted at each epoch
+    /// activation. Returns the number of evicted vote accounts. An evicted
+    /// vote account is cached again once it is stored. The counts are not
+    /// part of the snapshot, so these only cover the epochs activated since
+    /// the stakes were loaded; a restarted node evicts later, never earlier.
+    pub fn evict_idle_voters(&mut self, max_idle_epochs: u64) -> usize {
+        let idle_voters: Vec<Pubkey> = self
+            .idle_epochs
+            .iter()
+            .filter(|(vote_pubkey, epochs)| {
+                **epochs > max_idle_epochs
+                    && self.vote_accounts.get_delegated_stake(vote_pubkey) == 0
+            })
+            .map(|(vote_pubkey, _)| *vote_pubkey)
+            .collect();
+        for vote_pubkey in &idle_voters {
+            self.remove_vote_account(vote_pubkey);
+        }
+        idle_voters.len()
+    }
+
+    /// Merges the src stake account into dest, as the stake program's merge
+    /// instruction would: dest's lamports and delegated stake are increased
+    /// by src's, and src is removed from the cache. Both stake accounts must
+    /// be delegated to the same voter with the same activation and
+    /// deactivation epochs, so the voter's delegated stake is unchanged.
+    pub fn merge_stake_accounts(
+        &mut self,
+        dest: &Pubkey,
+        src: &Pubkey,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Result<(), Error> {
+        if dest == src {
+            return Err(Error::MergeSelf(*src));
+        }
+        let get_stake_account = |stake_pubkey: &Pubkey| {
+            self.stake_delegations
+                .get(stake_pubkey)
+                .ok_or(Error::StakeAccountNotFound(*stake_pubkey))
+        };
+        let dest_account = get_stake_account(dest)?;
+        let src_account = get_stake_account(src)?;
+        let dest_delegation = dest_account.delegation();
+        let src_delegation = src_account.delegation();
+        if dest_delegation.voter_pubkey != src_delegation.voter_pubkey {
+            return Err(Error::MergeVoterMismatch {
+                source_voter: src_delegation.voter_pubkey,
+                destination_voter: dest_delegation.voter_pubkey,
+            });
+        }
+        if dest_delegation.activation_epoch != src_delegation.activation_epoch
+            || dest_delegation.deactivation_epoch != src_delegation.deactivation_epoch
+        {
+            return Err(Error::MergeIncompatible {
+                source_account: *src,
+                destination_account: *dest,
+            });
+        }
+        let merged_account = Self::rebuild_stake_account(
+            dest_account,
+            dest_account.lamports() + src_account.lamports(),
+            dest_delegation.stake + src_delegation.stake,
+        )
+        .ok_or(Error::InvalidDelegation(*dest))?;
+        self.remove_stake_delegation(src, new_rate_activation_epoch);
+        self.upsert_stake_delegation(*dest, merged_account, new_rate_activation_epoch);
+        Ok(())
+    }
+
+    /// Splits amount lamports of delegated stake off the src stake account
+    /// into a new stake account, as the stake program's split instruction
+    /// would. The new stake account holds the split off stake on top of the
+    /// rent exempt reserve of src, which src pays for as well, and is
+    /// delegated to the same voter with the same activation and deactivation
+    /// epochs, so the voter's delegated stake is unchanged. The split is
+    /// rejected if src would not keep its rent exempt reserve on top of its
+    /// remaining stake, or would be left without lamports. Splitting off no
+    /// stake is an error.
+    pub fn split_stake_account(
+        &mut self,
+        src: &Pubkey,
+        new: &Pubkey,
+        amount: u64,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Result<(), Error> {
+        let src_account = self
+            .stake_delegations
+            .get(src)
+            .ok_or(Error::StakeAccountNotFound(*src))?;
+        if amount == 0 || amount > src_account.delegation().stake {
+            return Err(Error::InvalidDelegation(*src));
+        }
+        if src == new || self.stake_delegations.contains_key(new) {
+            return Err(Error::InvalidDelegation(*new));
+        }
+        let StakeStateV2::Stake(meta, _, _) = src_account.stake_state() else {
+            return Err(Error::InvalidDelegation(*src));
+        };
+        let rent_exempt_reserve = meta.rent_exempt_reserve;
+        let split_lamports = amount.saturating_add(rent_exempt_reserve);
+        let remaining_stake = src_account.delegation().stake - amount;
+        let remaining_lamports = src_account.lamports().saturating_sub(split_lamports);
+        if remaining_lamports == 0
+            || remaining_lamports < rent_exempt_reserve.saturating_add(remaining_stake)
+        {
+            return Err(Error::SplitBelowRentExemptReserve(*src));
+        }
+        let remaining_account =
+            Self::rebuild_stake_account(src_account, remaining_lamports, remaining_stake)
+                .ok_or(Error::InvalidDelegation(*src))?;
+        let split_account = Self::rebuild_stake_account(src_account, split_lamports, amount)
+            .ok_or(Error::InvalidDelegation(*src))?;
+        self.upsert_stake_delegation(*src, remaining_account, new_rate_activation_epoch);
+        self.upsert_stake_delegation(*new, split_account, new_rate_activation_epoch);
+        Ok(())
+    }
+
+    /// Returns a copy of the stake account with the given lamports and
+    /// delegated stake.
+    fn rebuild_stake_account(
+        stake_account: &StakeAccount,
+        lamports: u64,
+        delegated_stake: u64,
+    ) -> Option<StakeAccount> {
+        let (mut account, stake_state): (AccountSharedData, StakeStateV2) =
+            stake_account.clone().into();
+        let StakeStateV2::Stake(meta, mut stake, stake_flags) = stake_state else {
+            return None;
+        };
+        stake.delegation.stake = delegated_stake;
+        account.set_lamports(lamports);
+        account
+            .set_state(&StakeStateV2::Stake(meta, stake, stake_flags))
+            .ok()?;
+        StakeAccount::try_from(account).ok()
+    }
+
+    /// Sum the stakes that point to the given voter_pubkey
+    fn calculate_stake(
+        stake_delegations: &ImHashMap<Pubkey, StakeAccount>,
+        voter_pubkey: &Pubkey,
+        epoch: Epoch,
+        stake_history: &StakeHistory,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> u64 {
+        stake_delegations
+            .values()
+            .map(StakeAccount::delegation)
+            .filter(|delegation| &delegation.voter_pubkey == voter_pubkey)
+            .map(|delegation| delegation.stake(epoch, stake_history, new_rate_activation_epoch))
+            .sum()
+    }
+
+    /// Sum the lamports of the vote accounts and the delegated stake
+    pub(crate) fn vote_balance_and_staked(&self) -> u64 {
+        let get_stake = |stake_account: &StakeAccount| stake_account.delegation().stake;
+        let get_lamports = |(_, vote_account): (_, &VoteAccount)| vote_account.lamports();
+
+        self.stake_delegations.values().map(get_stake).sum::<u64>()
+            + self.vote_accounts.iter().map(get_lamports).sum::<u64>()
+    }
+
+    fn remove_vote_account(&mut self, vote_pubkey: &Pubkey) -> Option<VoteAccount> {
+        if self.idle_epochs.contains_key(vote_pubkey) {
+            self.idle_epochs.remove(vote_pubkey);
+        }
+        self.vote_accounts.remove(vote_pubkey).map(|(_, a)| a)
+    }
+
+    fn remove_stake_delegation(
+        &mut self,
+        stake_pubkey: &Pubkey,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        if let Some(stake_account) = self.stake_delegations.remove(stake_pubkey) {
+            let removed_delegation = stake_account.delegation();
+            let removed_stake = removed_delegation.stake(
+                self.epoch,
+                &self.stake_history,
+                new_rate_activation_epoch,
+            );
+            self.vote_accounts
+                .sub_stake(&removed_delegation.voter_pubkey, removed_stake);
+        }
+    }
+
+    fn upsert_vote_account(
+        &mut self,
         vote_pubkey: &Pubkey,
         vote_account: VoteAccount,
         new_rate_activation_epoch: Option<Epoch>,
     ) -> Option<VoteAccount> {
         debug_assert_ne!(vote_account.lamports(), 0u64);
 
+        // VoteAccounts::insert updates an existing entry in place, keeping
+        // its cached stake; the stake is only calculated for new entries.
         let stake_delegations = &self.stake_delegations;
         self.vote_accounts.insert(*vote_pubkey, vote_account, || {
             Self::calculate_stake(
@@ -446,6 +2226,10 @@ impl Stakes<StakeAccount> {
         let delegation = stake_account.delegation();
         let voter_pubkey = delegation.voter_pubkey;
         let stake = delegation.stake(self.epoch, &self.stake_history, new_rate_activation_epoch);
+        // The voter is no longer idle once stake returns.
+        if stake != 0 && self.idle_epochs.contains_key(&voter_pubkey) {
+            self.idle_epochs.remove(&voter_pubkey);
+        }
         match self.stake_delegations.insert(stake_pubkey, stake_account) {
             None => self.vote_accounts.add_stake(&voter_pubkey, stake),
             Some(old_stake_account) => {
@@ -469,6 +2253,19 @@ impl Stakes<StakeAccount> {
         thread_pool: &ThreadPool,
         stake_rewards: &[StakeReward],
         new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        self.merge_stake_rewards(thread_pool, stake_rewards, None);
+        self.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+    }
+
+    /// Updates the stake delegations with the rewarded stake accounts,
+    /// dropping zero lamport accounts and, if min_stake is given, the
+    /// delegations below min_stake lamports.
+    fn merge_stake_rewards(
+        &mut self,
+        thread_pool: &ThreadPool,
+        stake_rewards: &[StakeReward],
+        min_stake: Option<u64>,
     ) {
         let stake_delegations: Vec<_> = thread_pool.install(|| {
             stake_rewards
@@ -485,16 +2282,10 @@ impl Stakes<StakeAccount> {
             .collect::<HashMap<Pubkey, StakeAccount>>()
             .into_iter()
             .filter(|(_, account)| account.lamports() != 0u64)
+            .filter(|(_, account)| {
+                min_stake.map_or(true, |min_stake| account.delegation().stake >= min_stake)
+            })
             .collect();
-        let stake_delegations: Vec<_> = self.stake_delegations.values().collect();
-        self.vote_accounts = refresh_vote_accounts(
-            thread_pool,
-            self.epoch,
-            &self.vote_accounts,
-            &stake_delegations,
-            &self.stake_history,
-            new_rate_activation_epoch,
-        );
     }
 
     pub(crate) fn stake_delegations(&self) -> &ImHashMap<Pubkey, StakeAccount> {
@@ -505,601 +2296,5019 @@ impl Stakes<StakeAccount> {
         let vote_account = self.vote_accounts.find_max_by_delegated_stake()?;
         Some(vote_account.node_pubkey())
     }
-}
 
-impl StakesEnum {
-    pub fn vote_accounts(&self) -> &VoteAccounts {
-        match self {
-            StakesEnum::Accounts(stakes) => stakes.vote_accounts(),
-            StakesEnum::Delegations(stakes) => stakes.vote_accounts(),
-            StakesEnum::Stakes(stakes) => stakes.vote_accounts(),
-        }
+    /// Returns the minimum and maximum activation epochs across all the
+    /// delegated stake accounts, or None if there are no delegations.
+    /// Bootstrap stakes report an activation epoch of `Epoch::MAX`.
+    pub fn activation_epoch_range(&self) -> Option<(Epoch, Epoch)> {
+        self.stake_delegations
+            .values()
+            .map(|stake_account| stake_account.delegation().activation_epoch)
+            .minmax()
+            .into_option()
     }
 
-    pub(crate) fn staked_nodes(&self) -> Arc<HashMap<Pubkey, u64>> {
-        match self {
-            StakesEnum::Accounts(stakes) => stakes.staked_nodes(),
-            StakesEnum::Delegations(stakes) => stakes.staked_nodes(),
-            StakesEnum::Stakes(stakes) => stakes.staked_nodes(),
+    /// Diagnostic which recomputes each voter's stake from the stake
+    /// delegations and returns the voters whose cached delegated stake
+    /// differs, along with the signed difference `cached - recomputed`.
+    pub fn accounting_drift(&self, new_rate_activation_epoch: Option<Epoch>) -> Vec<(Pubkey, i64)> {
+        let mut delegated_stakes = HashMap::<Pubkey, u64>::new();
+        for stake_account in self.stake_delegations.values() {
+            let delegation = stake_account.delegation();
+            *delegated_stakes.entry(delegation.voter_pubkey).or_default() +=
+                delegation.stake(self.epoch, &self.stake_history, new_rate_activation_epoch);
         }
+        self.vote_accounts
+            .delegated_stakes()
+            .filter_map(|(vote_pubkey, cached_stake)| {
+                let stake = delegated_stakes
+                    .get(vote_pubkey)
+                    .copied()
+                    .unwrap_or_default();
+                let drift = i128::from(cached_stake) - i128::from(stake);
+                (drift != 0).then(|| {
+                    let drift =
+                        i64::try_from(drift).unwrap_or(if drift < 0 { i64::MIN } else { i64::MAX });
+                    (*vote_pubkey, drift)
+                })
+            })
+            .collect()
     }
-}
 
-/// This conversion is very memory intensive so should only be used in
-/// development contexts.
-#[cfg(feature = "dev-context-only-utils")]
-impl From<Stakes<StakeAccount>> for Stakes<Delegation> {
-    fn from(stakes: Stakes<StakeAccount>) -> Self {
-        let stake_delegations = stakes
-            .stake_delegations
-            .into_iter()
-            .map(|(pubkey, stake_account)| (pubkey, *stake_account.delegation()))
-            .collect();
-        Self {
-            vote_accounts: stakes.vote_accounts,
-            stake_delegations,
-            unused: stakes.unused,
-            epoch: stakes.epoch,
-            stake_history: stakes.stake_history,
-        }
+    /// Returns what total_stake would be if these stakes were at the given
+    /// epoch, using the current stake history, without updating the cache.
+    /// Consistent with total_stake, only stake delegated to cached vote
+    /// accounts is counted.
+    pub fn total_stake_at(&self, epoch: Epoch, new_rate_activation_epoch: Option<Epoch>) -> u64 {
+        self.stake_delegations
+            .values()
+            .map(StakeAccount::delegation)
+            .filter(|delegation| self.vote_accounts.get(&delegation.voter_pubkey).is_some())
+            .map(|delegation| {
+                delegation.stake(epoch, &self.stake_history, new_rate_activation_epoch)
+            })
+            .sum()
     }
-}
 
-/// This conversion is very memory intensive so should only be used in
-/// development contexts.
-#[cfg(feature = "dev-context-only-utils")]
-impl From<Stakes<StakeAccount>> for Stakes<Stake> {
-    fn from(stakes: Stakes<StakeAccount>) -> Self {
-        let stake_delegations = stakes
+    /// Returns the stake delegated to the cached vote accounts which is not
+    /// effective at the current epoch, i.e. delegated minus total_stake. This
+    /// is mostly stake which is still warming up, but also includes stake
+    /// which is cooling down or has been deactivated.
+    pub fn warmup_gap(&self) -> u64 {
+        let delegated_stake: u64 = self
             .stake_delegations
-            .into_iter()
-            .map(|(pubkey, stake_account)| (pubkey, *stake_account.stake()))
-            .collect();
-        Self {
-            vote_accounts: stakes.vote_accounts,
-            stake_delegations,
-            unused: stakes.unused,
-            epoch: stakes.epoch,
-            stake_history: stakes.stake_history,
-        }
+            .values()
+            .map(StakeAccount::delegation)
+            .filter(|delegation| self.vote_accounts.get(&delegation.voter_pubkey).is_some())
+            .map(|delegation| delegation.stake)
+            .sum();
+        delegated_stake.saturating_sub(self.total_stake())
     }
-}
 
-/// This conversion is memory intensive so should only be used in development
-/// contexts.
-#[cfg(feature = "dev-context-only-utils")]
-impl From<Stakes<Stake>> for Stakes<Delegation> {
-    fn from(stakes: Stakes<Stake>) -> Self {
-        let stake_delegations = stakes
+    /// Models slashing the given voter, e.g. for economic simulations. The
+    /// delegated stake of each stake account delegated to the voter is
+    /// reduced by the given fraction, clamped to [0, 1], and the same amount
+    /// is deducted from the account's lamports. Returns the total lamports
+    /// removed.
+    pub fn apply_slashing(
+        &mut self,
+        voter: &Pubkey,
+        fraction: f64,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> u64 {
+        if fraction.is_nan() {
+            return 0;
+        }
+        let fraction = fraction.clamp(0.0, 1.0);
+        let stake_accounts: Vec<_> = self
             .stake_delegations
-            .into_iter()
-            .map(|(pubkey, stake)| (pubkey, stake.delegation))
+            .iter()
+            .filter(|(_, stake_account)| &stake_account.delegation().voter_pubkey == voter)
+            .map(|(stake_pubkey, stake_account)| (*stake_pubkey, stake_account.clone()))
+            .collect();
+        let mut total_slashed = 0;
+        for (stake_pubkey, stake_account) in stake_accounts {
+            let delegated_stake = stake_account.delegation().stake;
+            let slashed = (delegated_stake as f64 * fraction) as u64;
+            let Some(stake_account) = Self::rebuild_stake_account(
+                &stake_account,
+                stake_account.lamports().saturating_sub(slashed),
+                delegated_stake - slashed,
+            ) else {
+                continue;
+            };
+            if stake_account.lamports() == 0 {
+                self.remove_stake_delegation(&stake_pubkey, new_rate_activation_epoch);
+            } else {
+                self.upsert_stake_delegation(
+                    stake_pubkey,
+                    stake_account,
+                    new_rate_activation_epoch,
+                );
+            }
+            total_slashed += slashed;
+        }
+        total_slashed
+    }
+
+    /// A cheap checksum of the stakes state, for quickly telling apart
+    /// stakes which differ. The checksum is independent of insertion order,
+    /// so equal stakes always have equal checksums, but different stakes may
+    /// collide. It is only stable within a single process.
+    pub fn checksum(&self) -> u64 {
+        fn hash_account(pubkey: &Pubkey, account: &AccountSharedData, hasher: &mut impl Hasher) {
+            pubkey.hash(hasher);
+            account.lamports().hash(hasher);
+            account.owner().hash(hasher);
+            account.executable().hash(hasher);
+            account.rent_epoch().hash(hasher);
+            account.data().hash(hasher);
+        }
+        let vote_accounts =
+            self.vote_accounts
+                .as_ref()
+                .iter()
+                .map(|(vote_pubkey, (stake, vote_account))| {
+                    let mut hasher = DefaultHasher::new();
+                    0u8.hash(&mut hasher);
+                    hash_account(vote_pubkey, vote_account.account(), &mut hasher);
+                    stake.hash(&mut hasher);
+                    hasher.finish()
+                });
+        let stake_delegations =
+            self.stake_delegations
+                .iter()
+                .map(|(stake_pubkey, stake_account)| {
+                    let mut hasher = DefaultHasher::new();
+                    1u8.hash(&mut hasher);
+                    hash_account(stake_pubkey, stake_account.account(), &mut hasher);
+                    hasher.finish()
+                });
+        let mut hasher = DefaultHasher::new();
+        self.unused.hash(&mut hasher);
+        self.epoch.hash(&mut hasher);
+        for (epoch, entry) in self.stake_history.iter() {
+            epoch.hash(&mut hasher);
+            entry.effective.hash(&mut hasher);
+            entry.activating.hash(&mut hasher);
+            entry.deactivating.hash(&mut hasher);
+        }
+        vote_accounts
+            .chain(stake_delegations)
+            .fold(hasher.finish(), BitXor::bitxor)
+    }
+
+    /// Replaces the stake history used for warmup and cooldown, e.g. with the
+    /// cluster's StakeHistory sysvar, and recalculates the delegated stake of
+    /// all the vote accounts accordingly.
+    pub fn set_stake_history(
+        &mut self,
+        stake_history: StakeHistory,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        self.stake_history = stake_history;
+        self.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+    }
+
+    /// Debugging aid to diagnose drift of the cache from the accounts store.
+    /// Fetches each cached vote and stake account and returns the pubkeys of
+    /// those which are missing or differ from the fetched account.
+    pub fn validate_against<F>(&self, fetch: F) -> Result<(), Vec<Pubkey>>
+    where
+        F: Fn(&Pubkey) -> Option<AccountSharedData>,
+    {
+        let is_stale = |pubkey: &Pubkey, account: &AccountSharedData| match fetch(pubkey) {
+            Some(fetched) => !accounts_equal(&fetched, account),
+            None => true,
+        };
+        let vote_accounts = self
+            .vote_accounts
+            .iter()
+            .map(|(vote_pubkey, vote_account)| (vote_pubkey, vote_account.account()));
+        let stake_delegations = self
+            .stake_delegations
+            .iter()
+            .map(|(stake_pubkey, stake_account)| (stake_pubkey, stake_account.account()));
+        let mismatches: Vec<Pubkey> = vote_accounts
+            .chain(stake_delegations)
+            .filter(|(pubkey, account)| is_stale(pubkey, account))
+            .map(|(pubkey, _)| *pubkey)
             .collect();
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(mismatches)
+        }
+    }
+
+    /// Returns the stake delegations to the given voter. The stake
+    /// delegations are not indexed by voter, so this scans all of them; the
+    /// per-voter queries built on it are meant for offline analysis, and are
+    /// not to be used on the replay or RPC paths.
+    fn delegations_to<'a>(
+        &'a self,
+        voter_pubkey: &'a Pubkey,
+    ) -> impl Iterator<Item = (&'a Pubkey, &'a StakeAccount)> + 'a {
+        self.stake_delegations
+            .iter()
+            .filter(move |(_, stake_account)| {
+                &stake_account.delegation().voter_pubkey == voter_pubkey
+            })
+    }
+
+    /// Returns the voter's delegated stake divided by the number of stake
+    /// accounts delegated to it, or None if there are no such stake accounts.
+    /// This scans all the stake delegations, see Stakes::delegations_to.
+    pub fn average_delegation(&self, voter: &Pubkey) -> Option<f64> {
+        let num_stake_accounts = self.delegations_to(voter).count();
+        (num_stake_accounts != 0).then(|| {
+            self.vote_accounts.get_delegated_stake(voter) as f64 / num_stake_accounts as f64
+        })
+    }
+
+    /// Consumes the stakes, returning its parts without cloning the
+    /// underlying maps.
+    pub fn into_parts(self) -> StakesParts {
+        StakesParts {
+            vote_accounts: Arc::from(&self.vote_accounts),
+            stake_delegations: self.stake_delegations,
+            unused: self.unused,
+            epoch: self.epoch,
+            stake_history: self.stake_history,
+        }
+    }
+
+    /// Reconstructs stakes from its parts, trusting the delegated stake of
+    /// each vote account as given. The parts are assumed to be consistent,
+    /// e.g. as returned by into_parts; otherwise use from_parts_verified.
+    pub fn from_parts(parts: StakesParts) -> Self {
+        let StakesParts {
+            vote_accounts,
+            stake_delegations,
+            unused,
+            epoch,
+            stake_history,
+        } = parts;
         Self {
-            vote_accounts: stakes.vote_accounts,
+            vote_accounts: VoteAccounts::from(vote_accounts),
             stake_delegations,
-            unused: stakes.unused,
-            epoch: stakes.epoch,
-            stake_history: stakes.stake_history,
+            unused,
+            epoch,
+            stake_history,
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
         }
     }
-}
 
-/// This conversion is memory intensive so should only be used in development
-/// contexts.
-#[cfg(feature = "dev-context-only-utils")]
-impl From<StakesEnum> for Stakes<Delegation> {
-    fn from(stakes: StakesEnum) -> Self {
-        match stakes {
-            StakesEnum::Accounts(stakes) => stakes.into(),
-            StakesEnum::Delegations(stakes) => stakes,
-            StakesEnum::Stakes(stakes) => stakes.into(),
+    /// Same as from_parts, but recalculates the delegated stake of each vote
+    /// account from the stake delegations.
+    pub fn from_parts_verified(
+        parts: StakesParts,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Self {
+        let mut stakes = Self::from_parts(parts);
+        stakes.recalculate_vote_account_stakes(thread_pool, new_rate_activation_epoch);
+        stakes
+    }
+
+    /// Returns the delegated stake of the given stake account, or None if the
+    /// stake account is unknown or not delegated.
+    pub fn stake_amount(&self, stake_pubkey: &Pubkey) -> Option<u64> {
+        self.stake_delegations
+            .get(stake_pubkey)
+            .map(|stake_account| stake_account.delegation().stake)
+    }
+
+    /// Returns the voter the given stake account is delegated to, or None if
+    /// the stake account is unknown or not delegated.
+    pub fn voter_of(&self, stake_pubkey: &Pubkey) -> Option<Pubkey> {
+        self.stake_delegations
+            .get(stake_pubkey)
+            .map(|stake_account| stake_account.delegation().voter_pubkey)
+    }
+
+    /// Number of cached stake accounts which are delegated. Undelegated
+    /// stake accounts, e.g. StakeStateV2::Initialized, are not counted.
+    pub fn active_delegation_count(&self) -> usize {
+        self.stake_delegations.len()
+    }
+
+    /// Returns the effective stake of the given stake account at each epoch
+    /// in `start_epoch..=end_epoch`, applying warmup and cooldown using the
+    /// current stake history. Returns an empty vector if the stake account
+    /// is not delegated.
+    pub fn stake_timeline(
+        &self,
+        stake_pubkey: &Pubkey,
+        start_epoch: Epoch,
+        end_epoch: Epoch,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Vec<(Epoch, u64)> {
+        let Some(stake_account) = self.stake_delegations.get(stake_pubkey) else {
+            return Vec::default();
+        };
+        let delegation = stake_account.delegation();
+        (start_epoch..=end_epoch)
+            .map(|epoch| {
+                let stake = delegation.stake(epoch, &self.stake_history, new_rate_activation_epoch);
+                (epoch, stake)
+            })
+            .collect()
+    }
+
+    /// Returns the stake delegations whose voter is not a cached vote
+    /// account, e.g. because the vote account was closed or has not been
+    /// stored yet, as (stake_pubkey, voter_pubkey, delegated stake) sorted by
+    /// stake pubkey.
+    pub fn orphan_delegations(&self) -> Vec<(Pubkey, Pubkey, u64)> {
+        self.stake_delegations
+            .iter()
+            .map(|(stake_pubkey, stake_account)| (stake_pubkey, stake_account.delegation()))
+            .filter(|(_, delegation)| self.vote_accounts.get(&delegation.voter_pubkey).is_none())
+            .map(|(stake_pubkey, delegation)| {
+                (*stake_pubkey, delegation.voter_pubkey, delegation.stake)
+            })
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Removes the given vote and stake accounts from the cache, the same as
+    /// removing them one at a time, but updates the delegated stake of each
+    /// affected vote account only once.
+    pub fn bulk_remove(&mut self, pubkeys: &[Pubkey], new_rate_activation_epoch: Option<Epoch>) {
+        let mut removed_stakes = HashMap::<Pubkey, u64>::new();
+        for pubkey in pubkeys {
+            self.remove_vote_account(pubkey);
+            let Some(stake_account) = self.stake_delegations.remove(pubkey) else {
+                continue;
+            };
+            let delegation = stake_account.delegation();
+            let stake =
+                delegation.stake(self.epoch, &self.stake_history, new_rate_activation_epoch);
+            *removed_stakes.entry(delegation.voter_pubkey).or_default() += stake;
+        }
+        for (voter_pubkey, stake) in removed_stakes {
+            self.vote_accounts.sub_stake(&voter_pubkey, stake);
         }
     }
-}
 
-impl From<Stakes<StakeAccount>> for StakesEnum {
-    fn from(stakes: Stakes<StakeAccount>) -> Self {
-        Self::Accounts(stakes)
+    /// Returns the stake delegations as (stake_pubkey, voter_pubkey,
+    /// delegated stake) edges, sorted by stake pubkey, e.g. to export the
+    /// delegation graph.
+    pub fn delegation_edges(&self) -> Vec<(Pubkey, Pubkey, u64)> {
+        self.delegations_iter().sorted_unstable().collect()
     }
-}
 
-impl From<Stakes<Delegation>> for StakesEnum {
-    fn from(stakes: Stakes<Delegation>) -> Self {
-        Self::Delegations(stakes)
+    /// Iterates over the stake delegations as (stake_pubkey, voter_pubkey,
+    /// delegated stake), in arbitrary order.
+    pub fn delegations_iter(&self) -> impl Iterator<Item = (Pubkey, Pubkey, u64)> + '_ {
+        self.stake_delegations
+            .iter()
+            .map(|(stake_pubkey, stake_account)| {
+                let delegation = stake_account.delegation();
+                (*stake_pubkey, delegation.voter_pubkey, delegation.stake)
+            })
     }
-}
 
-// Two StakesEnums are equal as long as they represent the same delegations;
-// whether these delegations are stored as StakeAccounts or Delegations.
-// Therefore, if one side is Stakes<StakeAccount> and the other is a
-// Stakes<Delegation> we convert the former one to Stakes<Delegation> before
-// comparing for equality.
-#[cfg(feature = "dev-context-only-utils")]
-impl PartialEq<StakesEnum> for StakesEnum {
-    fn eq(&self, other: &StakesEnum) -> bool {
-        match (self, other) {
-            (Self::Accounts(stakes), Self::Accounts(other)) => stakes == other,
-            (Self::Delegations(stakes), Self::Delegations(other)) => stakes == other,
-            (Self::Stakes(stakes), Self::Stakes(other)) => stakes == other,
-            (stakes, other) => {
-                let stakes = Stakes::<Delegation>::from(stakes.clone());
-                let other = Stakes::<Delegation>::from(other.clone());
-                stakes == other
+    /// Returns the number of stake accounts delegated to each cached vote
+    /// account, including the vote accounts which have none. This counts all
+    /// the voters in a single scan of the stake delegations, rather than
+    /// scanning them once per voter, see Stakes::delegations_to; it is still
+    /// meant for offline analysis only.
+    pub fn delegator_counts(&self) -> HashMap<Pubkey, usize> {
+        let mut delegator_counts: HashMap<Pubkey, usize> = self
+            .vote_accounts
+            .iter()
+            .map(|(vote_pubkey, _)| (*vote_pubkey, 0))
+            .collect();
+        for stake_account in self.stake_delegations.values() {
+            let voter_pubkey = &stake_account.delegation().voter_pubkey;
+            if let Some(count) = delegator_counts.get_mut(voter_pubkey) {
+                *count += 1;
             }
         }
+        delegator_counts
     }
-}
 
-fn refresh_vote_accounts(
-    thread_pool: &ThreadPool,
-    epoch: Epoch,
-    vote_accounts: &VoteAccounts,
-    stake_delegations: &[&StakeAccount],
-    stake_history: &StakeHistory,
-    new_rate_activation_epoch: Option<Epoch>,
-) -> VoteAccounts {
-    type StakesHashMap = HashMap</*voter:*/ Pubkey, /*stake:*/ u64>;
-    fn merge(mut stakes: StakesHashMap, other: StakesHashMap) -> StakesHashMap {
-        if stakes.len() < other.len() {
-            return merge(other, stakes);
+    /// Returns the stake account with the largest delegated stake among those
+    /// delegated to the given voter, ties broken by the lowest pubkey, or None
+    /// if the voter has no stake delegations. This scans all the stake
+    /// delegations, see Stakes::delegations_to.
+    pub fn largest_delegation(&self, voter: &Pubkey) -> Option<(Pubkey, u64)> {
+        self.delegations_to(voter)
+            .map(|(stake_pubkey, stake_account)| (*stake_pubkey, stake_account.delegation().stake))
+            .max_by_key(|&(stake_pubkey, stake)| (stake, std::cmp::Reverse(stake_pubkey)))
+    }
+
+    /// Returns the stake delegations which are not yet fully activated at
+    /// the given epoch, i.e. still warming up or activating at a later
+    /// epoch, as (stake_pubkey, delegated stake, activation_epoch) sorted by
+    /// stake pubkey.
+    pub fn activating_stakes(
+        &self,
+        epoch: Epoch,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Vec<(Pubkey, u64, Epoch)> {
+        self.stake_delegations
+            .iter()
+            .map(|(stake_pubkey, stake_account)| (stake_pubkey, stake_account.delegation()))
+            .filter(|(_, delegation)| {
+                let is_pending = !delegation.is_bootstrap() && delegation.activation_epoch > epoch;
+                is_pending
+                    || delegation
+                        .stake_activating_and_deactivating(
+                            epoch,
+                            &self.stake_history,
+                            new_rate_activation_epoch,
+                        )
+                        .activating
+                        != 0
+            })
+            .map(|(stake_pubkey, delegation)| {
+                (*stake_pubkey, delegation.stake, delegation.activation_epoch)
+            })
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Post-condition check that no vote account's cached stake exceeds the
+    /// total stake delegated to it, which is the most its effective stake
+    /// can be, e.g. because the cached stake wrapped around. Returns the
+    /// offending voters, sorted by pubkey.
+    pub fn check_bounds(&self) -> Result<(), Vec<Pubkey>> {
+        let mut delegated_stakes = HashMap::<Pubkey, u64>::new();
+        for stake_account in self.stake_delegations.values() {
+            let delegation = stake_account.delegation();
+            let delegated_stake = delegated_stakes.entry(delegation.voter_pubkey).or_default();
+            *delegated_stake = delegated_stake.saturating_add(delegation.stake);
         }
-        for (pubkey, stake) in other {
-            *stakes.entry(pubkey).or_default() += stake;
+        let voters: Vec<Pubkey> = self
+            .vote_accounts
+            .delegated_stakes()
+            .filter(|(vote_pubkey, stake)| {
+                *stake
+                    > delegated_stakes
+                        .get(*vote_pubkey)
+                        .copied()
+                        .unwrap_or_default()
+            })
+            .map(|(vote_pubkey, _)| *vote_pubkey)
+            .sorted_unstable()
+            .collect();
+        if voters.is_empty() {
+            Ok(())
+        } else {
+            Err(voters)
         }
-        stakes
     }
-    let delegated_stakes = thread_pool.install(|| {
-        stake_delegations
-            .par_iter()
-            .fold(HashMap::default, |mut delegated_stakes, stake_account| {
+
+    /// Returns the stake accounts, sorted by pubkey, whose delegated stake
+    /// is fully effective at the given epoch, i.e. done warming up and not
+    /// deactivating, using the current stake history.
+    pub fn fully_active_delegations(
+        &self,
+        as_of_epoch: Epoch,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Vec<Pubkey> {
+        self.stake_delegations
+            .iter()
+            .filter(|(_, stake_account)| {
                 let delegation = stake_account.delegation();
-                let entry = delegated_stakes.entry(delegation.voter_pubkey).or_default();
-                *entry += delegation.stake(epoch, stake_history, new_rate_activation_epoch);
-                delegated_stakes
+                let StakeActivationStatus {
+                    effective,
+                    activating,
+                    deactivating,
+                } = delegation.stake_activating_and_deactivating(
+                    as_of_epoch,
+                    &self.stake_history,
+                    new_rate_activation_epoch,
+                );
+                effective == delegation.stake && activating == 0 && deactivating == 0
             })
-            .reduce(HashMap::default, merge)
-    });
-    vote_accounts
-        .iter()
-        .map(|(&vote_pubkey, vote_account)| {
-            let delegated_stake = delegated_stakes
-                .get(&vote_pubkey)
-                .copied()
-                .unwrap_or_default();
-            (vote_pubkey, (delegated_stake, vote_account.clone()))
-        })
-        .collect()
-}
+            .map(|(stake_pubkey, _)| *stake_pubkey)
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Drops any per-voter state left behind for the voters of the stake
+    /// delegations whose voter is not a cached vote account, see
+    /// orphan_delegations, and returns how many such delegations there are.
+    /// The delegations themselves are kept, so that the cache still matches
+    /// the stake accounts in accounts-db, and their stake is counted again
+    /// once the vote account is cached again. Such delegations contribute no
+    /// stake, so total_stake is unchanged.
+    pub fn prune_orphan_delegations(&mut self) -> usize {
+        let orphan_delegations = self.orphan_delegations();
+        for (_, voter_pubkey, _) in &orphan_delegations {
+            debug_assert_eq!(self.vote_accounts.get_delegated_stake(voter_pubkey), 0);
+            if self.idle_epochs.contains_key(voter_pubkey) {
+                self.idle_epochs.remove(voter_pubkey);
+            }
+            if self.vote_account_slots.contains_key(voter_pubkey) {
+                self.vote_account_slots.remove(voter_pubkey);
+            }
+        }
+        orphan_delegations.len()
+    }
+
+    /// Same as check_and_store, but returns the account previously cached
+    /// for the pubkey, if any, as a vote or stake account.
+    pub fn store_returning_previous(
+        &mut self,
+        pubkey: &Pubkey,
+        account: &impl ReadableAccount,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Option<AccountSharedData> {
+        let previous = self.cached_account(pubkey);
+        self.check_and_store(pubkey, account, new_rate_activation_epoch);
+        previous
+    }
+
+    fn cached_account(&self, pubkey: &Pubkey) -> Option<AccountSharedData> {
+        if let Some(vote_account) = self.vote_accounts.get(pubkey) {
+            return Some(vote_account.account().clone());
+        }
+        self.stake_delegations
+            .get(pubkey)
+            .map(StakeAccount::account)
+            .cloned()
+    }
+
+    /// Returns the cached vote accounts along with the number of stake
+    /// accounts delegated to each, see delegator_counts, sorted by the
+    /// number of delegators in descending order, ties broken by pubkey. Like
+    /// delegator_counts, this scans all the stake delegations, and is meant
+    /// for offline analysis only.
+    pub fn sorted_by_delegator_count(&self) -> Vec<(Pubkey, usize)> {
+        self.delegator_counts()
+            .into_iter()
+            .sorted_unstable_by_key(|&(vote_pubkey, count)| (std::cmp::Reverse(count), vote_pubkey))
+            .collect()
+    }
+
+    /// Returns the changes of the stake delegations from previous to self,
+    /// sorted by stake account pubkey. A stake account delegated to a
+    /// different voter is reported as Redelegated, whether or not its
+    /// stake changed as well.
+    pub fn delegation_changes(&self, previous: &Self) -> Vec<DelegationChange> {
+        let changes = self
+            .stake_delegations
+            .iter()
+            .filter_map(|(stake_pubkey, stake_account)| {
+                let delegation = stake_account.delegation();
+                let Some(previous_stake_account) = previous.stake_delegations.get(stake_pubkey)
+                else {
+                    return Some(DelegationChange::Added {
+                        stake_pubkey: *stake_pubkey,
+                        voter_pubkey: delegation.voter_pubkey,
+                        stake: delegation.stake,
+                    });
+                };
+                let previous_delegation = previous_stake_account.delegation();
+                if previous_delegation.voter_pubkey != delegation.voter_pubkey {
+                    Some(DelegationChange::Redelegated {
+                        stake_pubkey: *stake_pubkey,
+                        old_voter_pubkey: previous_delegation.voter_pubkey,
+                        new_voter_pubkey: delegation.voter_pubkey,
+                        old_stake: previous_delegation.stake,
+                        new_stake: delegation.stake,
+                    })
+                } else if previous_delegation.stake != delegation.stake {
+                    Some(DelegationChange::StakeChanged {
+                        stake_pubkey: *stake_pubkey,
+                        voter_pubkey: delegation.voter_pubkey,
+                        old_stake: previous_delegation.stake,
+                        new_stake: delegation.stake,
+                    })
+                } else {
+                    None
+                }
+            });
+        let removals = previous
+            .stake_delegations
+            .iter()
+            .filter(|(stake_pubkey, _)| !self.stake_delegations.contains_key(*stake_pubkey))
+            .map(|(stake_pubkey, stake_account)| DelegationChange::Removed {
+                stake_pubkey: *stake_pubkey,
+                voter_pubkey: stake_account.delegation().voter_pubkey,
+                stake: stake_account.delegation().stake,
+            });
+        changes
+            .chain(removals)
+            .sorted_unstable_by_key(|change| match change {
+                DelegationChange::Added { stake_pubkey, .. }
+                | DelegationChange::Removed { stake_pubkey, .. }
+                | DelegationChange::Redelegated { stake_pubkey, .. }
+                | DelegationChange::StakeChanged { stake_pubkey, .. } => *stake_pubkey,
+            })
+            .collect()
+    }
+
+    /// Same as check_and_store, but if the account is cached as a vote
+    /// account, also records the slot it was stored at, see
+    /// vote_account_last_slot.
+    pub fn store_at_slot(
+        &mut self,
+        pubkey: &Pubkey,
+        account: &impl ReadableAccount,
+        slot: Slot,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        self.check_and_store(pubkey, account, new_rate_activation_epoch);
+        self.record_vote_account_slot(pubkey, account, slot);
+    }
+
+    fn record_vote_account_slot(
+        &mut self,
+        pubkey: &Pubkey,
+        account: &impl ReadableAccount,
+        slot: Slot,
+    ) {
+        if solana_vote_program::check_id(account.owner())
+            && self.vote_accounts.get(pubkey).is_some()
+        {
+            self.vote_account_slots.insert(*pubkey, slot);
+        }
+    }
+
+    /// Returns the slot at which the cached vote account was last stored,
+    /// or None if it was last stored through other than store_at_slot or
+    /// StakesCache::check_and_store.
+    pub fn vote_account_last_slot(&self, voter_pubkey: &Pubkey) -> Option<Slot> {
+        self.vote_accounts.get(voter_pubkey)?;
+        self.vote_account_slots.get(voter_pubkey).copied()
+    }
+
+    /// Returns a copy of these stakes with additional fully active stake
+    /// delegated to the given voters, e.g. to model how allocating stake
+    /// across voters changes the stake distribution. Each allocation is
+    /// stored as a stake account at the given placeholder stake pubkey,
+    /// replacing any stake account already cached at that pubkey.
+    pub fn simulate_allocation(
+        &self,
+        allocation: &[(/*stake:*/ Pubkey, /*voter:*/ Pubkey, u64)],
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Self {
+        let mut stakes = self.clone();
+        for (stake_pubkey, voter_pubkey, stake) in allocation {
+            if *stake == 0 {
+                continue;
+            }
+            // Bootstrap delegations are fully active from any epoch.
+            let stake_state = StakeStateV2::Stake(
+                Meta::default(),
+                Stake {
+                    delegation: Delegation::new(voter_pubkey, *stake, Epoch::MAX),
+                    credits_observed: 0,
+                },
+                StakeFlags::empty(),
+            );
+            let account = AccountSharedData::new_data_with_space(
+                *stake,
+                &stake_state,
+                StakeStateV2::size_of(),
+                &solana_stake_program::id(),
+            )
+            .unwrap();
+            stakes.check_and_store(stake_pubkey, &account, new_rate_activation_epoch);
+        }
+        stakes
+    }
+
+    /// Stores the vote and stake accounts cached in overlay into these
+    /// stakes. Where both cache a different account for the same pubkey,
+    /// prefer_overlay is invoked with the pubkey, the account in these
+    /// stakes and the account in overlay, and the overlay account is only
+    /// stored if it returns true.
+    pub fn merge_with<F>(
+        &mut self,
+        overlay: &Self,
+        prefer_overlay: F,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) where
+        F: Fn(&Pubkey, &AccountSharedData, &AccountSharedData) -> bool,
+    {
+        let vote_accounts = overlay
+            .vote_accounts
+            .iter()
+            .map(|(pubkey, vote_account)| (pubkey, vote_account.account()));
+        let stake_accounts = overlay
+            .stake_delegations
+            .iter()
+            .map(|(pubkey, stake_account)| (pubkey, stake_account.account()));
+        for (pubkey, account) in vote_accounts.chain(stake_accounts) {
+            if let Some(cached_account) = self.cached_account(pubkey) {
+                if accounts_equal(&cached_account, account)
+                    || !prefer_overlay(pubkey, &cached_account, account)
+                {
+                    continue;
+                }
+            }
+            self.check_and_store(pubkey, account, new_rate_activation_epoch);
+        }
+    }
+
+    /// Folds over the stake delegations, see delegations_iter, invoking f
+    /// with the accumulator, the stake account pubkey, the voter pubkey and
+    /// the delegated stake of each.
+    pub fn fold_delegations<A, F>(&self, init: A, mut f: F) -> A
+    where
+        F: FnMut(A, Pubkey, Pubkey, u64) -> A,
+    {
+        self.delegations_iter()
+            .fold(init, |acc, (stake_pubkey, voter_pubkey, stake)| {
+                f(acc, stake_pubkey, voter_pubkey, stake)
+            })
+    }
+
+    /// Removes the cached stake accounts for which predicate returns true,
+    /// see bulk_remove, and returns the removed accounts sorted by pubkey.
+    pub fn drain_stake_accounts<F>(
+        &mut self,
+        predicate: F,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Vec<(Pubkey, AccountSharedData)>
+    where
+        F: Fn(&Pubkey, &AccountSharedData) -> bool,
+    {
+        let stake_accounts = self
+            .stake_delegations
+            .iter()
+            .map(|(pubkey, stake_account)| (pubkey, stake_account.account()));
+        let drained: Vec<_> = stake_accounts
+            .filter(|(pubkey, account)| predicate(pubkey, account))
+            .map(|(pubkey, account)| (*pubkey, account.clone()))
+            .sorted_unstable_by_key(|(pubkey, _)| *pubkey)
+            .collect();
+        let pubkeys: Vec<_> = drained.iter().map(|(pubkey, _)| *pubkey).collect();
+        self.bulk_remove(&pubkeys, new_rate_activation_epoch);
+        drained
+    }
+
+    /// Returns a copy of these stakes advanced to the next epoch, see
+    /// StakesCache::activate_epoch, e.g. to preview the stake distribution
+    /// of the next epoch.
+    pub fn project_next_epoch(
+        &self,
+        thread_pool: &ThreadPool,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Self {
+        let mut stakes = self.clone();
+        stakes.activate_epoch(self.epoch + 1, thread_pool, new_rate_activation_epoch);
+        stakes
+    }
+
+    /// Returns the total delegated stake of the stake delegations grouped by
+    /// their activation epoch. Bootstrap delegations are grouped under
+    /// Epoch::MAX.
+    pub fn delegated_by_activation_epoch(&self) -> BTreeMap<Epoch, u64> {
+        let mut delegated_stakes = BTreeMap::<Epoch, u64>::new();
+        for stake_account in self.stake_delegations.values() {
+            let delegation = stake_account.delegation();
+            *delegated_stakes
+                .entry(delegation.activation_epoch)
+                .or_default() += delegation.stake;
+        }
+        delegated_stakes
+    }
+
+    /// Recalculates the delegated stake of the given vote account from the
+    /// stake delegations and overwrites the cached stake, e.g. to repair a
+    /// single vote account whose stake drifted, without recalculating the
+    /// stake of all the vote accounts.
+    pub fn reconcile_voter(
+        &mut self,
+        voter_pubkey: &Pubkey,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) {
+        if self.vote_accounts.get(voter_pubkey).is_none() {
+            return;
+        }
+        let stake = Self::calculate_stake(
+            &self.stake_delegations,
+            voter_pubkey,
+            self.epoch,
+            &self.stake_history,
+            new_rate_activation_epoch,
+        );
+        let cached_stake = self.vote_accounts.get_delegated_stake(voter_pubkey);
+        if stake > cached_stake {
+            self.vote_accounts
+                .add_stake(voter_pubkey, stake - cached_stake);
+        } else {
+            self.vote_accounts
+                .sub_stake(voter_pubkey, cached_stake - stake);
+        }
+    }
+
+    /// Models inflation, e.g. for economic simulations. The delegated stake
+    /// of each stake account grows by the given rate, and the same amount is
+    /// added to the account's lamports. Rates which are not positive are
+    /// ignored. Returns the total lamports added.
+    pub fn apply_inflation(&mut self, rate: f64, new_rate_activation_epoch: Option<Epoch>) -> u64 {
+        if rate.is_nan() || rate <= 0.0 {
+            return 0;
+        }
+        let stake_accounts: Vec<_> = self
+            .stake_delegations
+            .iter()
+            .map(|(stake_pubkey, stake_account)| (*stake_pubkey, stake_account.clone()))
+            .collect();
+        let mut total_inflation = 0;
+        for (stake_pubkey, stake_account) in stake_accounts {
+            let delegated_stake = stake_account.delegation().stake;
+            let inflation = (delegated_stake as f64 * rate) as u64;
+            let Some(stake_account) = Self::rebuild_stake_account(
+                &stake_account,
+                stake_account.lamports().saturating_add(inflation),
+                delegated_stake.saturating_add(inflation),
+            ) else {
+                continue;
+            };
+            self.upsert_stake_delegation(stake_pubkey, stake_account, new_rate_activation_epoch);
+            total_inflation += inflation;
+        }
+        total_inflation
+    }
+
+    /// Returns the groups of cached stake accounts which are identical, i.e.
+    /// the same account is stored under several pubkeys, each group sorted
+    /// by pubkey. Only meant for diagnostics: identical stake accounts under
+    /// distinct pubkeys are still counted separately.
+    pub fn duplicate_account_groups(&self) -> Vec<Vec<Pubkey>> {
+        let stake_accounts = self
+            .stake_delegations
+            .iter()
+            .map(|(pubkey, stake_account)| (pubkey, stake_account.account()));
+        let mut groups = HashMap::<_, Vec<Pubkey>>::new();
+        for (pubkey, account) in stake_accounts {
+            let key = (
+                account.lamports(),
+                account.owner(),
+                account.executable(),
+                account.rent_epoch(),
+                account.data(),
+            );
+            groups.entry(key).or_default().push(*pubkey);
+        }
+        groups
+            .into_values()
+            .filter(|pubkeys| pubkeys.len() > 1)
+            .map(|pubkeys| pubkeys.into_iter().sorted_unstable().collect::<Vec<_>>())
+            .sorted_unstable()
+            .collect()
+    }
+
+    /// Partitions these stakes into num_shards disjoint stakes by the hash
+    /// of the voter pubkey, e.g. to process them in parallel. Each vote
+    /// account lands in the same shard as the stake accounts delegated to
+    /// it, so each shard's delegated stakes are consistent on their own. The
+    /// shards can be combined back using concat.
+    pub fn shard_by_voter(&self, num_shards: usize) -> Vec<Self> {
+        if num_shards == 0 {
+            return Vec::default();
+        }
+        let shard_of = |pubkey: &Pubkey| {
+            let mut hasher = DefaultHasher::new();
+            pubkey.hash(&mut hasher);
+            (hasher.finish() % num_shards as u64) as usize
+        };
+        let mut vote_accounts = vec![VoteAccountsHashMap::default(); num_shards];
+        for (vote_pubkey, (stake, vote_account)) in self.vote_accounts.as_ref() {
+            vote_accounts[shard_of(vote_pubkey)]
+                .insert(*vote_pubkey, (*stake, vote_account.clone()));
+        }
+        let mut stake_delegations = vec![ImHashMap::default(); num_shards];
+        for (stake_pubkey, stake_account) in &self.stake_delegations {
+            let voter_pubkey = &stake_account.delegation().voter_pubkey;
+            stake_delegations[shard_of(voter_pubkey)].insert(*stake_pubkey, stake_account.clone());
+        }
+        vote_accounts
+            .into_iter()
+            .zip(stake_delegations)
+            .map(|(vote_accounts, stake_delegations)| Self {
+                vote_accounts: VoteAccounts::from(Arc::new(vote_accounts)),
+                stake_delegations,
+                unused: self.unused,
+                epoch: self.epoch,
+                stake_history: self.stake_history.clone(),
+                vote_account_slots: ImHashMap::default(),
+                idle_epochs: ImHashMap::default(),
+            })
+            .collect()
+    }
+
+    /// Returns the cached vote account for the given voter, if any, followed
+    /// by the stake accounts delegated to it sorted by pubkey, e.g. to export
+    /// everything needed to rebuild the voter's stake. This scans all the
+    /// stake delegations, see Stakes::delegations_to.
+    pub fn accounts_for_voter(&self, voter_pubkey: &Pubkey) -> Vec<(Pubkey, AccountSharedData)> {
+        let vote_account = self
+            .vote_accounts
+            .get(voter_pubkey)
+            .map(|vote_account| (*voter_pubkey, vote_account.account().clone()));
+        let stake_accounts = self
+            .delegations_to(voter_pubkey)
+            .map(|(stake_pubkey, stake_account)| (*stake_pubkey, stake_account.account().clone()))
+            .sorted_unstable_by_key(|(stake_pubkey, _)| *stake_pubkey);
+        vote_account.into_iter().chain(stake_accounts).collect()
+    }
+}
+
+impl StakesEnum {
+    pub fn vote_accounts(&self) -> &VoteAccounts {
+        match self {
+            StakesEnum::Accounts(stakes) => stakes.vote_accounts(),
+            StakesEnum::Delegations(stakes) => stakes.vote_accounts(),
+            StakesEnum::Stakes(stakes) => stakes.vote_accounts(),
+        }
+    }
+
+    pub(crate) fn staked_nodes(&self) -> Arc<HashMap<Pubkey, u64>> {
+        match self {
+            StakesEnum::Accounts(stakes) => stakes.staked_nodes(),
+            StakesEnum::Delegations(stakes) => stakes.staked_nodes(),
+            StakesEnum::Stakes(stakes) => stakes.staked_nodes(),
+        }
+    }
+}
+
+/// Stores each account as in Stakes::check_and_store. Stakes are calculated
+/// without a new warmup/cooldown rate activation epoch; call check_and_store
+/// directly if one is needed.
+impl Extend<(Pubkey, AccountSharedData)> for Stakes<StakeAccount> {
+    fn extend<I: IntoIterator<Item = (Pubkey, AccountSharedData)>>(&mut self, accounts: I) {
+        for (pubkey, account) in accounts {
+            self.check_and_store(&pubkey, &account, None);
+        }
+    }
+}
+
+impl FromIterator<(Pubkey, AccountSharedData)> for Stakes<StakeAccount> {
+    fn from_iter<I: IntoIterator<Item = (Pubkey, AccountSharedData)>>(accounts: I) -> Self {
+        let mut stakes = Self::default();
+        stakes.extend(accounts);
+        stakes
+    }
+}
+
+/// This conversion is very memory intensive so should only be used in
+/// development contexts.
+#[cfg(feature = "dev-context-only-utils")]
+impl From<Stakes<StakeAccount>> for Stakes<Delegation> {
+    fn from(stakes: Stakes<StakeAccount>) -> Self {
+        let stake_delegations = stakes
+            .stake_delegations
+            .into_iter()
+            .map(|(pubkey, stake_account)| (pubkey, *stake_account.delegation()))
+            .collect();
+        Self {
+            vote_accounts: stakes.vote_accounts,
+            stake_delegations,
+            unused: stakes.unused,
+            epoch: stakes.epoch,
+            stake_history: stakes.stake_history,
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
+        }
+    }
+}
+
+/// This conversion is very memory intensive so should only be used in
+/// development contexts.
+#[cfg(feature = "dev-context-only-utils")]
+impl From<Stakes<StakeAccount>> for Stakes<Stake> {
+    fn from(stakes: Stakes<StakeAccount>) -> Self {
+        let stake_delegations = stakes
+            .stake_delegations
+            .into_iter()
+            .map(|(pubkey, stake_account)| (pubkey, *stake_account.stake()))
+            .collect();
+        Self {
+            vote_accounts: stakes.vote_accounts,
+            stake_delegations,
+            unused: stakes.unused,
+            epoch: stakes.epoch,
+            stake_history: stakes.stake_history,
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
+        }
+    }
+}
+
+/// This conversion is memory intensive so should only be used in development
+/// contexts.
+#[cfg(feature = "dev-context-only-utils")]
+impl From<Stakes<Stake>> for Stakes<Delegation> {
+    fn from(stakes: Stakes<Stake>) -> Self {
+        let stake_delegations = stakes
+            .stake_delegations
+            .into_iter()
+            .map(|(pubkey, stake)| (pubkey, stake.delegation))
+            .collect();
+        Self {
+            vote_accounts: stakes.vote_accounts,
+            stake_delegations,
+            unused: stakes.unused,
+            epoch: stakes.epoch,
+            stake_history: stakes.stake_history,
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
+        }
+    }
+}
+
+/// This conversion is memory intensive so should only be used in development
+/// contexts.
+#[cfg(feature = "dev-context-only-utils")]
+impl From<StakesEnum> for Stakes<Delegation> {
+    fn from(stakes: StakesEnum) -> Self {
+        match stakes {
+            StakesEnum::Accounts(stakes) => stakes.into(),
+            StakesEnum::Delegations(stakes) => stakes,
+            StakesEnum::Stakes(stakes) => stakes.into(),
+        }
+    }
+}
+
+impl From<Stakes<StakeAccount>> for StakesEnum {
+    fn from(stakes: Stakes<StakeAccount>) -> Self {
+        Self::Accounts(stakes)
+    }
+}
+
+impl From<Stakes<Delegation>> for StakesEnum {
+    fn from(stakes: Stakes<Delegation>) -> Self {
+        Self::Delegations(stakes)
+    }
+}
+
+// Two StakesEnums are equal as long as they represent the same delegations;
+// whether these delegations are stored as StakeAccounts or Delegations.
+// Therefore, if one side is Stakes<StakeAccount> and the other is a
+// Stakes<Delegation> we convert the former one to Stakes<Delegation> before
+// comparing for equality.
+#[cfg(feature = "dev-context-only-utils")]
+impl PartialEq<StakesEnum> for StakesEnum {
+    fn eq(&self, other: &StakesEnum) -> bool {
+        match (self, other) {
+            (Self::Accounts(stakes), Self::Accounts(other)) => stakes == other,
+            (Self::Delegations(stakes), Self::Delegations(other)) => stakes == other,
+            (Self::Stakes(stakes), Self::Stakes(other)) => stakes == other,
+            (stakes, other) => {
+                let stakes = Stakes::<Delegation>::from(stakes.clone());
+                let other = Stakes::<Delegation>::from(other.clone());
+                stakes == other
+            }
+        }
+    }
+}
+
+/// Builds stakes from vote and stake accounts created on the fly, e.g. to set
+/// up test scenarios. Stake is delegated from bootstrap stake accounts, so it
+/// is fully active at any epoch.
+#[cfg(feature = "dev-context-only-utils")]
+#[derive(Debug, Default)]
+pub struct StakesBuilder {
+    epoch: Epoch,
+    vote_accounts: Vec<(Pubkey, AccountSharedData)>,
+    stake_accounts: Vec<(Pubkey, AccountSharedData)>,
+}
+
+#[cfg(feature = "dev-context-only-utils")]
+impl StakesBuilder {
+    pub fn with_epoch(mut self, epoch: Epoch) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Adds a vote account with the given node identity and commission.
+    pub fn with_validator(
+        mut self,
+        vote_pubkey: Pubkey,
+        node_pubkey: Pubkey,
+        commission: u8,
+    ) -> Self {
+        let vote_account = solana_vote_program::vote_state::create_account(
+            &vote_pubkey,
+            &node_pubkey,
+            commission,
+            1,
+        );
+        self.vote_accounts.push((vote_pubkey, vote_account));
+        self
+    }
+
+    /// Adds a stake account delegating the given stake to the vote account.
+    pub fn with_delegation(mut self, voter_pubkey: Pubkey, stake: u64) -> Self {
+        let vote_account = self
+            .vote_accounts
+            .iter()
+            .find(|(vote_pubkey, _)| vote_pubkey == &voter_pubkey)
+            .map(|(_, vote_account)| vote_account.clone())
+            .unwrap_or_else(|| {
+                solana_vote_program::vote_state::create_account(&voter_pubkey, &voter_pubkey, 0, 1)
+            });
+        let stake_pubkey = Pubkey::new_unique();
+        let stake_account = solana_stake_program::stake_state::create_account(
+            &stake_pubkey,
+            &voter_pubkey,
+            &vote_account,
+            &solana_sdk::rent::Rent::free(),
+            stake,
+        );
+        self.stake_accounts.push((stake_pubkey, stake_account));
+        self
+    }
+
+    pub fn build(self) -> Stakes<StakeAccount> {
+        let mut stakes = Stakes {
+            epoch: self.epoch,
+            ..Stakes::default()
+        };
+        for (pubkey, account) in self.vote_accounts.iter().chain(&self.stake_accounts) {
+            stakes.check_and_store(pubkey, account, None);
+        }
+        stakes
+    }
+}
+
+fn refresh_vote_accounts(
+    thread_pool: &ThreadPool,
+    epoch: Epoch,
+    vote_accounts: &VoteAccounts,
+    stake_delegations: &[&StakeAccount],
+    stake_history: &StakeHistory,
+    new_rate_activation_epoch: Option<Epoch>,
+) -> VoteAccounts {
+    type StakesHashMap = HashMap</*voter:*/ Pubkey, /*stake:*/ u64>;
+    fn merge(mut stakes: StakesHashMap, other: StakesHashMap) -> StakesHashMap {
+        if stakes.len() < other.len() {
+            return merge(other, stakes);
+        }
+        for (pubkey, stake) in other {
+            *stakes.entry(pubkey).or_default() += stake;
+        }
+        stakes
+    }
+    let delegated_stakes = thread_pool.install(|| {
+        stake_delegations
+            .par_iter()
+            .fold(HashMap::default, |mut delegated_stakes, stake_account| {
+                let delegation = stake_account.delegation();
+                let entry = delegated_stakes.entry(delegation.voter_pubkey).or_default();
+                *entry += delegation.stake(epoch, stake_history, new_rate_activation_epoch);
+                delegated_stakes
+            })
+            .reduce(HashMap::default, merge)
+    });
+    vote_accounts
+        .iter()
+        .map(|(&vote_pubkey, vote_account)| {
+            let delegated_stake = delegated_stakes
+                .get(&vote_pubkey)
+                .copied()
+                .unwrap_or_default();
+            (vote_pubkey, (delegated_stake, vote_account.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        rand::SeedableRng,
+        rayon::ThreadPoolBuilder,
+        solana_sdk::{account::WritableAccount, pubkey::Pubkey, rent::Rent, stake},
+        solana_stake_program::stake_state,
+        solana_vote_program::vote_state::{self, VoteState, VoteStateVersions},
+    };
+
+    //  set up some dummies for a staked node     ((     vote      )  (     stake     ))
+    pub(crate) fn create_staked_node_accounts(
+        stake: u64,
+    ) -> ((Pubkey, AccountSharedData), (Pubkey, AccountSharedData)) {
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+        let stake_pubkey = solana_sdk::pubkey::new_rand();
+        (
+            (vote_pubkey, vote_account),
+            (
+                stake_pubkey,
+                create_stake_account(stake, &vote_pubkey, &stake_pubkey),
+            ),
+        )
+    }
+
+    //   add stake to a vote_pubkey                               (   stake    )
+    pub(crate) fn create_stake_account(
+        stake: u64,
+        vote_pubkey: &Pubkey,
+        stake_pubkey: &Pubkey,
+    ) -> AccountSharedData {
+        stake_state::create_account(
+            stake_pubkey,
+            vote_pubkey,
+            &vote_state::create_account(vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1),
+            &Rent::free(),
+            stake,
+        )
+    }
+
+    fn create_warming_staked_node_accounts(
+        stake: u64,
+        epoch: Epoch,
+    ) -> ((Pubkey, AccountSharedData), (Pubkey, AccountSharedData)) {
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+        (
+            (vote_pubkey, vote_account),
+            create_warming_stake_account(stake, epoch, &vote_pubkey),
+        )
+    }
+
+    // add stake to a vote_pubkey                               (   stake    )
+    fn create_warming_stake_account(
+        stake: u64,
+        epoch: Epoch,
+        vote_pubkey: &Pubkey,
+    ) -> (Pubkey, AccountSharedData) {
+        let stake_pubkey = solana_sdk::pubkey::new_rand();
+        (
+            stake_pubkey,
+            stake_state::create_account_with_activation_epoch(
+                &stake_pubkey,
+                vote_pubkey,
+                &vote_state::create_account(vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1),
+                &Rent::free(),
+                stake,
+                epoch,
+            ),
+        )
+    }
+
+    // Recomputes the delegated stake of each cached vote account from the
+    // stake delegations and asserts it matches the cached stake.
+    fn check(stakes: &Stakes<StakeAccount>) {
+        for (vote_pubkey, stake) in stakes.vote_accounts.delegated_stakes() {
+            let expected_stake = Stakes::calculate_stake(
+                &stakes.stake_delegations,
+                vote_pubkey,
+                stakes.epoch,
+                &stakes.stake_history,
+                None,
+            );
+            assert_eq!(stake, expected_stake, "vote account: {vote_pubkey}");
+        }
+    }
+
+    #[test]
+    fn test_stakes_basic() {
+        for i in 0..4 {
+            let stakes_cache = StakesCache::new(Stakes {
+                epoch: i,
+                ..Stakes::default()
+            });
+
+            let ((vote_pubkey, vote_account), (stake_pubkey, mut stake_account)) =
+                create_staked_node_accounts(10);
+
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+            let stake = stake_state::stake_from(&stake_account).unwrap();
+            {
+                let stakes = stakes_cache.stakes();
+                check(&stakes);
+                let vote_accounts = stakes.vote_accounts();
+                assert!(vote_accounts.get(&vote_pubkey).is_some());
+                assert_eq!(
+                    vote_accounts.get_delegated_stake(&vote_pubkey),
+                    stake.stake(i, &StakeHistory::default(), None)
+                );
+            }
+
+            stake_account.set_lamports(42);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+            {
+                let stakes = stakes_cache.stakes();
+                check(&stakes);
+                let vote_accounts = stakes.vote_accounts();
+                assert!(vote_accounts.get(&vote_pubkey).is_some());
+                assert_eq!(
+                    vote_accounts.get_delegated_stake(&vote_pubkey),
+                    stake.stake(i, &StakeHistory::default(), None)
+                ); // stays old stake, because only 10 is activated
+            }
+
+            // activate more
+            let mut stake_account =
+                create_stake_account(42, &vote_pubkey, &solana_sdk::pubkey::new_rand());
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+            let stake = stake_state::stake_from(&stake_account).unwrap();
+            {
+                let stakes = stakes_cache.stakes();
+                check(&stakes);
+                let vote_accounts = stakes.vote_accounts();
+                assert!(vote_accounts.get(&vote_pubkey).is_some());
+                assert_eq!(
+                    vote_accounts.get_delegated_stake(&vote_pubkey),
+                    stake.stake(i, &StakeHistory::default(), None)
+                ); // now stake of 42 is activated
+            }
+
+            stake_account.set_lamports(0);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+            {
+                let stakes = stakes_cache.stakes();
+                check(&stakes);
+                let vote_accounts = stakes.vote_accounts();
+                assert!(vote_accounts.get(&vote_pubkey).is_some());
+                assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stakes_highest() {
+        let stakes_cache = StakesCache::default();
+
+        assert_eq!(stakes_cache.stakes().highest_staked_node(), None);
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+
+        let ((vote11_pubkey, vote11_account), (stake11_pubkey, stake11_account)) =
+            create_staked_node_accounts(20);
+
+        stakes_cache.check_and_store(&vote11_pubkey, &vote11_account, 0, None);
+        stakes_cache.check_and_store(&stake11_pubkey, &stake11_account, 0, None);
+
+        let vote11_node_pubkey = vote_state::from(&vote11_account).unwrap().node_pubkey;
+
+        let highest_staked_node = stakes_cache.stakes().highest_staked_node().copied();
+        assert_eq!(highest_staked_node, Some(vote11_node_pubkey));
+        check(&stakes_cache.stakes());
+    }
+
+    #[test]
+    fn test_stakes_vote_account_disappear_reappear() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+
+        let ((vote_pubkey, mut vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+        }
+
+        vote_account.set_lamports(0);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_none());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+        }
+
+        vote_account.set_lamports(1);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+        }
+
+        // Vote account too big
+        let cache_data = vote_account.data().to_vec();
+        let mut pushed = vote_account.data().to_vec();
+        pushed.push(0);
+        vote_account.set_data(pushed);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_none());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+        }
+
+        // Vote account uninitialized
+        let default_vote_state = VoteState::default();
+        let versioned = VoteStateVersions::new_current(default_vote_state);
+        vote_state::to(&versioned, &mut vote_account).unwrap();
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_none());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+        }
+
+        vote_account.set_data(cache_data);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+        }
+    }
+
+    #[test]
+    fn test_stakes_change_delegate() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        let ((vote_pubkey2, vote_account2), (_stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(10);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&vote_pubkey2, &vote_account2, 0, None);
+
+        // delegates to vote_pubkey
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+
+        let stake = stake_state::stake_from(&stake_account).unwrap();
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(
+                vote_accounts.get_delegated_stake(&vote_pubkey),
+                stake.stake(stakes.epoch, &stakes.stake_history, None)
+            );
+            assert!(vote_accounts.get(&vote_pubkey2).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 0);
+        }
+
+        // delegates to vote_pubkey2
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account2, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+            assert!(vote_accounts.get(&vote_pubkey2).is_some());
+            assert_eq!(
+                vote_accounts.get_delegated_stake(&vote_pubkey2),
+                stake.stake(stakes.epoch, &stakes.stake_history, None)
+            );
+        }
+    }
+    #[test]
+    fn test_stakes_multiple_stakers() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(10, &vote_pubkey, &stake_pubkey2);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        // delegates to vote_pubkey
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey2, &stake_account2, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 20);
+        }
+    }
+
+    #[test]
+    fn test_activate_epoch() {
+        let stakes_cache = StakesCache::default();
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        let stake = stake_state::stake_from(&stake_account).unwrap();
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert_eq!(
+                vote_accounts.get_delegated_stake(&vote_pubkey),
+                stake.stake(stakes.epoch, &stakes.stake_history, None)
+            );
+        }
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        stakes_cache.activate_epoch(3, &thread_pool, None);
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert_eq!(
+                vote_accounts.get_delegated_stake(&vote_pubkey),
+                stake.stake(stakes.epoch, &stakes.stake_history, None)
+            );
+        }
+    }
+
+    #[test]
+    fn test_stakes_not_delegate() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+        }
+
+        // not a stake account, and whacks above entry
+        stakes_cache.check_and_store(
+            &stake_pubkey,
+            &AccountSharedData::new(1, 0, &stake::program::id()),
+            0,
+            None,
+        );
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+        }
+    }
+
+    #[test]
+    fn test_stakes_undelegated_stake_account() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+
+        // initialized but not delegated, replaces the above delegation
+        let mut initialized_account = stake_state::create_lockup_stake_account(
+            &stake::state::Authorized::auto(&stake_pubkey),
+            &stake::state::Lockup::default(),
+            &Rent::free(),
+            10,
+        );
+        stakes_cache.check_and_store(&stake_pubkey, &initialized_account, 0, None);
+        assert!(stakes_cache
+            .undelegated_stake_accounts()
+            .contains_key(&stake_pubkey));
+        {
+            let stakes = stakes_cache.stakes();
+            assert!(stakes.stake_delegations().get(&stake_pubkey).is_none());
+            assert_eq!(stakes.voter_of(&stake_pubkey), None);
+            let vote_accounts = stakes.vote_accounts();
+            assert!(vote_accounts.get(&vote_pubkey).is_some());
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+            check(&stakes);
+        }
+
+        // child banks carry over the undelegated stake accounts
+        let child_stakes_cache = StakesCache::new_from_parent(&stakes_cache);
+        assert!(child_stakes_cache
+            .undelegated_stake_accounts()
+            .contains_key(&stake_pubkey));
+
+        // delegate again
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        assert!(stakes_cache.undelegated_stake_accounts().is_empty());
+        {
+            let stakes = stakes_cache.stakes();
+            assert!(stakes.stake_delegations().get(&stake_pubkey).is_some());
+            assert_eq!(stakes.voter_of(&stake_pubkey), Some(vote_pubkey));
+            let vote_accounts = stakes.vote_accounts();
+            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+            check(&stakes);
+        }
+        assert!(child_stakes_cache
+            .undelegated_stake_accounts()
+            .contains_key(&stake_pubkey));
+
+        // zero lamport accounts are evicted
+        stakes_cache.check_and_store(&stake_pubkey, &initialized_account, 0, None);
+        initialized_account.set_lamports(0);
+        stakes_cache.check_and_store(&stake_pubkey, &initialized_account, 0, None);
+        assert!(stakes_cache.undelegated_stake_accounts().is_empty());
+        {
+            let stakes = stakes_cache.stakes();
+            assert!(stakes.stake_delegations().is_empty());
+            check(&stakes);
+        }
+    }
+
+    #[test]
+    fn test_stakes_concat() {
+        let stakes_cache = StakesCache::default();
+        let partials = [StakesCache::default(), StakesCache::default()];
+
+        let mut accounts = vec![];
+        for stake in [10, 20, 30] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+            let stake_account2 = create_stake_account(stake, &vote_pubkey, &stake_pubkey2);
+            accounts.push((vote_pubkey, vote_account));
+            accounts.push((stake_pubkey, stake_account));
+            accounts.push((stake_pubkey2, stake_account2));
+        }
+        // Alternating shards puts a voter's stake accounts in both partials.
+        for (i, (pubkey, account)) in accounts.iter().enumerate() {
+            stakes_cache.check_and_store(pubkey, account, 0, None);
+            partials[i % 2].check_and_store(pubkey, account, None);
+        }
+
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let partials: Vec<Stakes<StakeAccount>> = partials
+            .iter()
+            .map(|partial| partial.stakes().clone())
+            .collect();
+        let stakes = Stakes::<StakeAccount>::concat(partials, &thread_pool, None);
+        assert_eq!(stakes, *stakes_cache.stakes());
+        let (vote_pubkey, _) = &accounts[0];
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(vote_pubkey), 20);
+        check(&stakes);
+
+        let stakes = Stakes::<StakeAccount>::concat(vec![], &thread_pool, None);
+        assert_eq!(stakes, Stakes::default());
+    }
+
+    #[test]
+    fn test_vote_balance_and_staked_empty() {
+        let stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(stakes.vote_balance_and_staked(), 0);
+    }
+
+    #[test]
+    fn test_vote_balance_and_staked_normal() {
+        let stakes_cache = StakesCache::default();
+        #[allow(non_local_definitions)]
+        impl Stakes<StakeAccount> {
+            fn vote_balance_and_warmed_staked(&self) -> u64 {
+                let vote_balance: u64 = self
+                    .vote_accounts
+                    .iter()
+                    .map(|(_pubkey, account)| account.lamports())
+                    .sum();
+                let warmed_stake: u64 = self
+                    .vote_accounts
+                    .delegated_stakes()
+                    .map(|(_pubkey, stake)| stake)
+                    .sum();
+                vote_balance + warmed_stake
+            }
+        }
+
+        let genesis_epoch = 0;
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_warming_staked_node_accounts(10, genesis_epoch);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+
+        {
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            assert_eq!(stakes.vote_balance_and_staked(), 11);
+            assert_eq!(stakes.vote_balance_and_warmed_staked(), 1);
+        }
+
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        for (epoch, expected_warmed_stake) in ((genesis_epoch + 1)..=3).zip(&[2, 3, 4]) {
+            stakes_cache.activate_epoch(epoch, &thread_pool, None);
+            // vote_balance_and_staked() always remain to return same lamports
+            // while vote_balance_and_warmed_staked() gradually increases
+            let stakes = stakes_cache.stakes();
+            check(&stakes);
+            assert_eq!(stakes.vote_balance_and_staked(), 11);
+            assert_eq!(
+                stakes.vote_balance_and_warmed_staked(),
+                *expected_warmed_stake
+            );
+        }
+    }
+
+    #[test]
+    fn test_activation_epoch_range() {
+        let stakes_cache = StakesCache::default();
+        assert_eq!(stakes_cache.stakes().activation_epoch_range(), None);
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_warming_staked_node_accounts(10, 7);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        assert_eq!(stakes_cache.stakes().activation_epoch_range(), Some((7, 7)));
+
+        for epoch in [3, 10] {
+            let (stake_pubkey, stake_account) =
+                create_warming_stake_account(10, epoch, &vote_pubkey);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        }
+        assert_eq!(
+            stakes_cache.stakes().activation_epoch_range(),
+            Some((3, 10))
+        );
+        check(&stakes_cache.stakes());
+    }
+
+    #[test]
+    fn test_rehydrate_accounts() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        for (pubkey, account) in [
+            (&vote_pubkey, &vote_account),
+            (&stake_pubkey, &stake_account),
+            (&vote_pubkey2, &vote_account2),
+            (&stake_pubkey2, &stake_account2),
+        ] {
+            stakes.check_and_store(pubkey, account, None);
+        }
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 10);
+        check(&stakes);
+
+        // vote_pubkey2 no longer exists in the source
+        let accounts = HashMap::from([
+            (vote_pubkey, vote_account),
+            (
+                stake_pubkey,
+                create_stake_account(42, &vote_pubkey, &stake_pubkey),
+            ),
+        ]);
+        stakes.rehydrate_accounts(
+            &[vote_pubkey, stake_pubkey, vote_pubkey2],
+            |pubkey| accounts.get(pubkey).cloned(),
+            None,
+        );
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 42);
+        assert!(stakes.vote_accounts().get(&vote_pubkey2).is_none());
+        // not rehydrated, so still cached
+        assert!(stakes.stake_delegations().contains_key(&stake_pubkey2));
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_top_k_share() {
+        let stakes_cache = StakesCache::default();
+        assert_eq!(stakes_cache.stakes().top_k_share(1), 0.0);
+
+        for stake in [10, 40, 20, 30] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        }
+        let stakes = stakes_cache.stakes();
+        check(&stakes);
+        assert_eq!(stakes.top_k_share(0), 0.0);
+        assert_eq!(stakes.top_k_share(1), 0.4);
+        assert_eq!(stakes.top_k_share(2), 0.7);
+        assert_eq!(stakes.top_k_share(4), 1.0);
+        assert_eq!(stakes.top_k_share(10), 1.0);
+    }
+
+    #[test]
+    fn test_redelegation_callback() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+        let redelegations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        stakes_cache.set_redelegation_callback({
+            let redelegations = redelegations.clone();
+            move |redelegation| redelegations.lock().unwrap().push(redelegation.clone())
+        });
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (_stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(10);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&vote_pubkey2, &vote_account2, 0, None);
+
+        // delegates to vote_pubkey
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        assert_eq!(
+            std::mem::take(&mut *redelegations.lock().unwrap()),
+            vec![Redelegation {
+                stake_pubkey,
+                old_voter: None,
+                new_voter: Some(vote_pubkey),
+                amount: 10,
+            }]
+        );
+
+        // storing the same delegation again is not a redelegation
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        assert!(redelegations.lock().unwrap().is_empty());
+
+        // delegates to vote_pubkey2
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account2, 0, None);
+        assert_eq!(
+            std::mem::take(&mut *redelegations.lock().unwrap()),
+            vec![Redelegation {
+                stake_pubkey,
+                old_voter: Some(vote_pubkey),
+                new_voter: Some(vote_pubkey2),
+                amount: 10,
+            }]
+        );
+        {
+            let stakes = stakes_cache.stakes();
+            assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 0);
+            assert_eq!(
+                stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+                10
+            );
+            check(&stakes);
+        }
+
+        // the callback is carried over to child banks
+        let child_stakes_cache = StakesCache::new_from_parent(&stakes_cache);
+        child_stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        assert_eq!(
+            std::mem::take(&mut *redelegations.lock().unwrap()),
+            vec![Redelegation {
+                stake_pubkey,
+                old_voter: Some(vote_pubkey2),
+                new_voter: Some(vote_pubkey),
+                amount: 10,
+            }]
+        );
+        check(&child_stakes_cache.stakes());
+
+        // zero lamports removes the delegation
+        let mut stake_account2 = stake_account2;
+        stake_account2.set_lamports(0);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account2, 0, None);
+        assert_eq!(
+            std::mem::take(&mut *redelegations.lock().unwrap()),
+            vec![Redelegation {
+                stake_pubkey,
+                old_voter: Some(vote_pubkey2),
+                new_voter: None,
+                amount: 10,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_node_identity() {
+        let stakes_cache = StakesCache::default();
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let node_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account = vote_state::create_account(&vote_pubkey, &node_pubkey, 0, 1);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        let stakes = stakes_cache.stakes();
+        assert_eq!(stakes.node_identity(&vote_pubkey), Some(node_pubkey));
+        assert_eq!(stakes.node_identity(&solana_sdk::pubkey::new_rand()), None);
+    }
+
+    #[test]
+    fn test_stakes_from_iter() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let accounts = vec![
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey2, vote_account2),
+        ];
+
+        let mut expected = Stakes::<StakeAccount>::default();
+        for (pubkey, account) in &accounts {
+            expected.check_and_store(pubkey, account, None);
+        }
+        let stakes: Stakes<StakeAccount> = accounts.iter().cloned().collect();
+        assert_eq!(stakes, expected);
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 10);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            20
+        );
+        check(&stakes);
+
+        let mut extended = Stakes::<StakeAccount>::default();
+        extended.extend(accounts);
+        assert_eq!(extended, expected);
+    }
+
+    #[test]
+    fn test_accounting_drift() {
+        let stakes_cache = StakesCache::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        for (pubkey, account) in [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ] {
+            stakes_cache.check_and_store(&pubkey, &account, 0, None);
+        }
+        assert!(stakes_cache.stakes().accounting_drift(None).is_empty());
+        check(&stakes_cache.stakes());
+
+        {
+            let mut stakes = stakes_cache.stakes.write().unwrap();
+            stakes.vote_accounts.add_stake(&vote_pubkey, 5);
+            stakes.vote_accounts.sub_stake(&vote_pubkey2, 7);
+        }
+        let mut drift = stakes_cache.stakes().accounting_drift(None);
+        drift.sort_unstable_by_key(|(_, drift)| *drift);
+        assert_eq!(drift, vec![(vote_pubkey2, -7), (vote_pubkey, 5)]);
+    }
+
+    #[test]
+    fn test_stake_extremes() {
+        let stakes_cache = StakesCache::default();
+        assert_eq!(stakes_cache.stakes().stake_extremes(), None);
+
+        // unstaked voters are not considered
+        let ((vote_pubkey, vote_account), _) = create_staked_node_accounts(10);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        assert_eq!(stakes_cache.stakes().stake_extremes(), None);
+
+        for stake in [30, 10, 20] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        }
+        assert_eq!(stakes_cache.stakes().stake_extremes(), Some((10, 30)));
+        check(&stakes_cache.stakes());
+    }
+
+    #[test]
+    fn test_evict_idle_voters() {
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut stakes = Stakes::<StakeAccount>::default();
+        // never staked, though it may well keep voting
+        let vote_pubkey1 = solana_sdk::pubkey::new_rand();
+        let vote_account1 =
+            vote_state::create_account(&vote_pubkey1, &solana_sdk::pubkey::new_rand(), 0, 1);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(10);
+        for (pubkey, account) in [
+            (vote_pubkey1, vote_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2.clone()),
+        ] {
+            stakes.check_and_store(&pubkey, &account, None);
+        }
+        check(&stakes);
+
+        for epoch in 1..=2 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+            assert_eq!(stakes.evict_idle_voters(2), 0);
+            check(&stakes);
+        }
+        stakes.activate_epoch(3, &thread_pool, None);
+        assert_eq!(stakes.evict_idle_voters(2), 1);
+        assert!(stakes.vote_accounts().get(&vote_pubkey1).is_none());
+        assert!(stakes.vote_accounts().get(&vote_pubkey2).is_some());
+        check(&stakes);
+
+        // vote_pubkey2 is unstaked for two epochs, then staked again, which
+        // resets its count.
+        let mut unstaked_account2 = stake_account2.clone();
+        unstaked_account2.set_lamports(0);
+        stakes.check_and_store(&stake_pubkey2, &unstaked_account2, None);
+        for epoch in 4..=5 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+        }
+        assert_eq!(stakes.idle_epochs.get(&vote_pubkey2), Some(&2));
+        stakes.check_and_store(&stake_pubkey2, &stake_account2, None);
+        assert!(stakes.idle_epochs.get(&vote_pubkey2).is_none());
+        check(&stakes);
+
+        stakes.check_and_store(&stake_pubkey2, &unstaked_account2, None);
+        for epoch in 6..=7 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+        }
+        assert_eq!(stakes.evict_idle_voters(2), 0);
+        stakes.activate_epoch(8, &thread_pool, None);
+        assert_eq!(stakes.evict_idle_voters(2), 1);
+        assert!(stakes.vote_accounts().get(&vote_pubkey2).is_none());
+        assert!(stakes.idle_epochs.is_empty());
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_total_stake_at() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 4,
+            ..Stakes::default()
+        });
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        // activating at the current epoch, so not yet effective
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_warming_staked_node_accounts(20, 4);
+        for (pubkey, account) in [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ] {
+            stakes_cache.check_and_store(&pubkey, &account, 0, None);
+        }
+
+        let stakes = stakes_cache.stakes();
+        check(&stakes);
+        assert_eq!(stakes.total_stake(), 10);
+        assert_eq!(stakes.total_stake_at(4, None), stakes.total_stake());
+        assert_eq!(stakes.total_stake_at(5, None), 30);
+        assert_eq!(stakes.epoch, 4);
+        assert_eq!(stakes.total_stake(), 10);
+    }
+
+    #[test]
+    fn test_voters_in_stake_range() {
+        let stakes_cache = StakesCache::default();
+        let voters: Vec<_> = [1, 10, 100, 1_000, 10_000, 100_000]
+            .into_iter()
+            .map(|stake| {
+                let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                    create_staked_node_accounts(stake);
+                stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+                stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+                (vote_pubkey, stake)
+            })
+            .collect();
+
+        let stakes = stakes_cache.stakes();
+        check(&stakes);
+        assert_eq!(
+            stakes.voters_in_stake_range(10, 1_000),
+            vec![voters[3], voters[2], voters[1]]
+        );
+        assert_eq!(
+            stakes.voters_in_stake_range(50, 50_000),
+            vec![voters[4], voters[3], voters[2]]
+        );
+        assert!(stakes.voters_in_stake_range(2, 9).is_empty());
+        assert!(stakes.voters_in_stake_range(100, 10).is_empty());
+    }
+
+    #[test]
+    fn test_vote_accounts_by_authorized_withdrawer() {
+        let stakes_cache = StakesCache::default();
+        let withdrawer = solana_sdk::pubkey::new_rand();
+        let withdrawer2 = solana_sdk::pubkey::new_rand();
+        let mut vote_pubkeys: Vec<_> = [withdrawer, withdrawer, withdrawer2]
+            .iter()
+            .map(|withdrawer| {
+                let vote_pubkey = solana_sdk::pubkey::new_rand();
+                let vote_account = vote_state::create_account_with_authorized(
+                    &solana_sdk::pubkey::new_rand(),
+                    &vote_pubkey,
+                    withdrawer,
+                    0,
+                    1,
+                );
+                stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+                vote_pubkey
+            })
+            .collect();
+
+        let stakes = stakes_cache.stakes();
+        let mut by_withdrawer = stakes.vote_accounts_by_authorized_withdrawer(&withdrawer);
+        by_withdrawer.sort_unstable();
+        let vote_pubkey2 = vote_pubkeys.pop().unwrap();
+        vote_pubkeys.sort_unstable();
+        assert_eq!(by_withdrawer, vote_pubkeys);
+        assert_eq!(
+            stakes.vote_accounts_by_authorized_withdrawer(&withdrawer2),
+            vec![vote_pubkey2]
+        );
+        assert!(stakes
+            .vote_accounts_by_authorized_withdrawer(&solana_sdk::pubkey::new_rand())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_apply_slashing() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(30, &vote_pubkey, &stake_pubkey2);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(40);
+        for (pubkey, account) in [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey3, vote_account3),
+            (stake_pubkey3, stake_account3),
+        ] {
+            stakes.check_and_store(&pubkey, &account, None);
+        }
+
+        assert_eq!(stakes.apply_slashing(&vote_pubkey, 0.5, None), 20);
+        check(&stakes);
+        let stake_delegations = stakes.stake_delegations();
+        for (stake_pubkey, stake) in [(stake_pubkey, 5), (stake_pubkey2, 15)] {
+            let stake_account = &stake_delegations[&stake_pubkey];
+            assert_eq!(stake_account.delegation().stake, stake);
+            assert_eq!(stake_account.lamports(), stake);
+        }
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 20);
+        // other voters are not affected
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey3),
+            40
+        );
+
+        // fraction is clamped to 1
+        assert_eq!(stakes.apply_slashing(&vote_pubkey, 2.0, None), 20);
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 0);
+        assert!(stakes.stake_delegations().get(&stake_pubkey).is_none());
+        assert_eq!(stakes.apply_slashing(&vote_pubkey3, -1.0, None), 0);
+        assert_eq!(stakes.apply_slashing(&vote_pubkey3, f64::NAN, None), 0);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey3),
+            40
+        );
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let accounts: Vec<_> = (1..=4)
+            .flat_map(|stake| {
+                let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                    create_staked_node_accounts(stake);
+                [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+            })
+            .collect();
+        let stakes: Stakes<StakeAccount> = accounts.iter().cloned().collect();
+        let stakes_reversed: Stakes<StakeAccount> = accounts.iter().rev().cloned().collect();
+        assert_eq!(stakes, stakes_reversed);
+        assert_eq!(stakes.checksum(), stakes_reversed.checksum());
+        assert_eq!(stakes.checksum(), stakes.clone().checksum());
+
+        // changing the lamports of a single stake account
+        let (stake_pubkey, stake_account) = &accounts[1];
+        let mut stake_account = stake_account.clone();
+        stake_account.checked_add_lamports(1).unwrap();
+        let mut other = stakes.clone();
+        other.check_and_store(stake_pubkey, &stake_account, None);
+        check(&other);
+        assert_ne!(stakes.checksum(), other.checksum());
+
+        // removing a vote account
+        let mut other = stakes.clone();
+        other.remove_vote_account(&accounts[0].0);
+        check(&other);
+        assert_ne!(stakes.checksum(), other.checksum());
+
+        // advancing the epoch
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut other = stakes.clone();
+        other.activate_epoch(1, &thread_pool, None);
+        check(&other);
+        assert_ne!(stakes.checksum(), other.checksum());
+    }
+
+    #[test]
+    fn test_patch_from() {
+        let accounts: Vec<_> = (1..=4)
+            .flat_map(|stake| {
+                let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                    create_staked_node_accounts(stake);
+                [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+            })
+            .collect();
+        let base: Stakes<StakeAccount> = accounts.iter().cloned().collect();
+        assert!(base.patch_from(&base).is_empty());
+
+        let mut stakes = base.clone();
+        // remove a vote account and a stake account
+        stakes.rehydrate_accounts(&[accounts[0].0, accounts[3].0], |_| None, None);
+        // redelegate a stake account
+        let (stake_pubkey, stake_account) = &accounts[5];
+        let stake_account = {
+            let mut stake_account = stake_account.clone();
+            let mut stake_state: stake::state::StakeStateV2 = stake_account.state().unwrap();
+            if let stake::state::StakeStateV2::Stake(_, stake, _) = &mut stake_state {
+                stake.delegation.voter_pubkey = accounts[6].0;
+            }
+            stake_account.set_state(&stake_state).unwrap();
+            stake_account
+        };
+        stakes.check_and_store(stake_pubkey, &stake_account, None);
+        // add a new voter
+        let ((vote_pubkey, vote_account), (new_stake_pubkey, new_stake_account)) =
+            create_staked_node_accounts(5);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        stakes.check_and_store(&new_stake_pubkey, &new_stake_account, None);
+        assert_ne!(stakes, base);
+        check(&stakes);
+
+        let patch = stakes.patch_from(&base);
+        assert_eq!(patch.len(), 5);
+        let mut patched = base.clone();
+        patched.apply_patch(patch, None);
+        assert_eq!(patched, stakes);
+        check(&patched);
+    }
+
+    #[test]
+    fn test_set_stake_history() {
+        let stakes_cache = StakesCache::new(Stakes {
+            epoch: 5,
+            ..Stakes::default()
+        });
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_warming_staked_node_accounts(20, 4);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        // Without any history the stake is assumed to be fully effective.
+        assert_eq!(
+            stakes_cache
+                .stakes()
+                .vote_accounts()
+                .get_delegated_stake(&vote_pubkey),
+            20
+        );
+
+        let mut stake_history = StakeHistory::default();
+        stake_history.add(
+            4,
+            solana_sdk::stake_history::StakeHistoryEntry {
+                effective: 1_000_000,
+                activating: 1_000_000,
+                deactivating: 0,
+            },
+        );
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut stakes = stakes_cache.stakes().clone();
+        stakes.set_stake_history(stake_history.clone(), &thread_pool, None);
+
+        let delegation = stake_state::stake_from(&stake_account).unwrap().delegation;
+        let stake = delegation.stake(5, &stake_history, None);
+        assert!(0 < stake && stake < 20);
+        assert_eq!(stakes.history(), &stake_history);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            stake
+        );
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_weighted_subset() {
+        let stakes_cache = StakesCache::default();
+        let voters: Vec<_> = [10, 100, 1_000, 10_000]
+            .into_iter()
+            .map(|stake| {
+                let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                    create_staked_node_accounts(stake);
+                stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+                stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+                vote_pubkey
+            })
+            .collect();
+        // unstaked voters are never selected
+        let ((vote_pubkey, vote_account), _) = create_staked_node_accounts(10);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+
+        let stakes = stakes_cache.stakes();
+        check(&stakes);
+        let mut rng = rand_chacha::ChaChaRng::seed_from_u64(42);
+        let mut counts = HashMap::<Pubkey, usize>::new();
+        for _ in 0..1_000 {
+            let subset = stakes.weighted_subset(2, &mut rng);
+            assert_eq!(subset.len(), 2);
+            assert_ne!(subset[0], subset[1]);
+            for vote_pubkey in subset {
+                *counts.entry(vote_pubkey).or_default() += 1;
+            }
+        }
+        assert!(!counts.contains_key(&vote_pubkey));
+        let count = |vote_pubkey: &Pubkey| counts.get(vote_pubkey).copied().unwrap_or_default();
+        for (vote_pubkey, other) in voters.iter().tuple_windows() {
+            assert!(count(vote_pubkey) < count(other));
+        }
+
+        let mut subset = stakes.weighted_subset(10, &mut rng);
+        subset.sort_unstable();
+        let mut voters = voters;
+        voters.sort_unstable();
+        assert_eq!(subset, voters);
+        assert!(stakes.weighted_subset(0, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_restore_vote_account_keeps_cached_stake() {
+        let stakes_cache = StakesCache::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        // Make the cached stake differ from what would be recalculated from
+        // the stake delegations.
+        stakes_cache
+            .stakes
+            .write()
+            .unwrap()
+            .vote_accounts
+            .add_stake(&vote_pubkey, 5);
+
+        let mut vote_account = vote_account;
+        for _ in 0..100 {
+            vote_account.checked_add_lamports(1).unwrap();
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            let stakes = stakes_cache.stakes();
+            let cached = stakes.vote_accounts().get(&vote_pubkey).unwrap();
+            assert_eq!(cached.lamports(), vote_account.lamports());
+            assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 15);
+        }
+    }
+
+    #[test]
+    fn test_validate_against() {
+        let accounts: HashMap<_, _> = (1..=3)
+            .flat_map(|stake| {
+                let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                    create_staked_node_accounts(stake);
+                [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+            })
+            .collect();
+        let stakes: Stakes<StakeAccount> = accounts.clone().into_iter().collect();
+        check(&stakes);
+        assert_eq!(
+            stakes.validate_against(|pubkey| accounts.get(pubkey).cloned()),
+            Ok(())
+        );
+
+        // one stale stake account and one account missing from the source
+        let mut source = accounts.clone();
+        let (stake_pubkey, stake_account) = source
+            .iter_mut()
+            .find(|(_, account)| solana_stake_program::check_id(account.owner()))
+            .unwrap();
+        let stake_pubkey = *stake_pubkey;
+        stake_account.checked_add_lamports(1).unwrap();
+        let vote_pubkey = *source
+            .keys()
+            .find(|pubkey| solana_vote_program::check_id(accounts[*pubkey].owner()))
+            .unwrap();
+        source.remove(&vote_pubkey);
+
+        let mut mismatches = stakes
+            .validate_against(|pubkey| source.get(pubkey).cloned())
+            .unwrap_err();
+        mismatches.sort_unstable();
+        let mut expected = vec![stake_pubkey, vote_pubkey];
+        expected.sort_unstable();
+        assert_eq!(mismatches, expected);
+    }
+
+    #[test]
+    fn test_average_delegation() {
+        let stakes_cache = StakesCache::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(25, &vote_pubkey, &stake_pubkey2);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        assert_eq!(stakes_cache.stakes().average_delegation(&vote_pubkey), None);
+
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey2, &stake_account2, 0, None);
+        let stakes = stakes_cache.stakes();
+        check(&stakes);
+        assert_eq!(stakes.average_delegation(&vote_pubkey), Some(17.5));
+        assert_eq!(
+            stakes.average_delegation(&solana_sdk::pubkey::new_rand()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_into_parts_from_parts() {
+        let accounts: Vec<_> = (1..=3)
+            .flat_map(|stake| {
+                let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                    create_staked_node_accounts(stake);
+                [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+            })
+            .collect();
+        let stakes: Stakes<StakeAccount> = Stakes {
+            unused: 7,
+            ..accounts.iter().cloned().collect()
+        };
+        assert_eq!(Stakes::from_parts(stakes.clone().into_parts()), stakes);
+
+        // from_parts trusts the given stakes whereas from_parts_verified
+        // recalculates them.
+        let mut parts = stakes.clone().into_parts();
+        Arc::make_mut(&mut parts.vote_accounts)
+            .get_mut(&accounts[0].0)
+            .unwrap()
+            .0 = 42;
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let unverified = Stakes::from_parts(StakesParts {
+            vote_accounts: parts.vote_accounts.clone(),
+            stake_delegations: parts.stake_delegations.clone(),
+            unused: parts.unused,
+            epoch: parts.epoch,
+            stake_history: parts.stake_history.clone(),
+        });
+        assert_eq!(
+            unverified
+                .vote_accounts()
+                .get_delegated_stake(&accounts[0].0),
+            42
+        );
+        let verified = Stakes::from_parts_verified(parts, &thread_pool, None);
+        assert_eq!(verified, stakes);
+        check(&verified);
+    }
+
+    #[test]
+    fn test_stake_state_counts() {
+        let stakes_cache = StakesCache::default();
+        assert_eq!(
+            stakes_cache.stake_state_counts(),
+            StakeStateCounts::default()
+        );
+
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        let uninitialized_account =
+            AccountSharedData::new(10, StakeStateV2::size_of(), &solana_stake_program::id());
+        stakes_cache.check_and_store(
+            &solana_sdk::pubkey::new_rand(),
+            &uninitialized_account,
+            0,
+            None,
+        );
+        let initialized_account = stake_state::create_lockup_stake_account(
+            &stake::state::Authorized::auto(&stake_pubkey),
+            &stake::state::Lockup::default(),
+            &Rent::free(),
+            10,
+        );
+        for _ in 0..2 {
+            stakes_cache.check_and_store(
+                &solana_sdk::pubkey::new_rand(),
+                &initialized_account,
+                0,
+                None,
+            );
+        }
+        let mut rewards_pool_account = uninitialized_account.clone();
+        rewards_pool_account
+            .set_state(&StakeStateV2::RewardsPool)
+            .unwrap();
+        stakes_cache.check_and_store(
+            &solana_sdk::pubkey::new_rand(),
+            &rewards_pool_account,
+            0,
+            None,
+        );
+
+        assert_eq!(
+            stakes_cache.stake_state_counts(),
+            StakeStateCounts {
+                uninitialized: 1,
+                initialized: 2,
+                stake: 1,
+                rewards_pool: 1,
+            }
+        );
+        check(&stakes_cache.stakes());
+    }
+
+    #[test]
+    fn test_reconcile_epoch_boundary() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(1_000);
+        let dust_pubkey = solana_sdk::pubkey::new_rand();
+        let dust_account = create_stake_account(10, &vote_pubkey, &dust_pubkey);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(100);
+        let (warming_pubkey, warming_account) = create_warming_stake_account(200, 0, &vote_pubkey2);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (dust_pubkey, dust_account),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (warming_pubkey, warming_account),
+        ]
+        .into_iter()
+        .collect();
+        let stake_rewards = vec![StakeReward {
+            stake_pubkey: stake_pubkey2,
+            stake_reward_info: solana_sdk::reward_info::RewardInfo {
+                reward_type: solana_sdk::reward_type::RewardType::Staking,
+                lamports: 50,
+                post_balance: 150,
+                commission: None,
+            },
+            stake_account: create_stake_account(150, &vote_pubkey2, &stake_pubkey2),
+        }];
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+
+        let mut expected = stakes.clone();
+        expected.activate_epoch(1, &thread_pool, None);
+        expected.update_stake_accounts(&thread_pool, &stake_rewards, None);
+        expected.prune_stake_delegations(50, &thread_pool, None);
+
+        let stakes_cache = StakesCache::new(stakes.clone());
+        let mut stakes = stakes;
+        let total_stake = stakes.total_stake();
+        stakes.reconcile_epoch_boundary(1, &stake_rewards, Some(50), &thread_pool, None);
+        assert_eq!(stakes, expected);
+        assert_eq!(stakes.epoch, 1);
+        assert!(stakes.stake_delegations().get(&dust_pubkey).is_none());
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            1_000
+        );
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            150 + stakes.stake_delegations()[&warming_pubkey]
+                .delegation()
+                .stake(1, &stakes.stake_history, None)
+        );
+        check(&stakes);
+
+        // the observers are notified the same as when activating the epoch
+        let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        stakes_cache.observe_total_stake_change(0.0, {
+            let changes = changes.clone();
+            move |old, new| changes.lock().unwrap().push((old, new))
+        });
+        stakes_cache.reconcile_epoch_boundary(1, &stake_rewards, Some(50), &thread_pool, None);
+        assert_eq!(*stakes_cache.stakes(), expected);
+        assert_ne!(total_stake, expected.total_stake());
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(total_stake, expected.total_stake())]
+        );
+    }
+
+    #[test]
+    fn test_stake_amount() {
+        let stakes_cache = StakesCache::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        let initialized_pubkey = solana_sdk::pubkey::new_rand();
+        let initialized_account = stake_state::create_lockup_stake_account(
+            &stake::state::Authorized::auto(&initialized_pubkey),
+            &stake::state::Lockup::default(),
+            &Rent::free(),
+            10,
+        );
+        stakes_cache.check_and_store(&initialized_pubkey, &initialized_account, 0, None);
+
+        let stakes = stakes_cache.stakes();
+        check(&stakes);
+        assert_eq!(stakes.stake_amount(&stake_pubkey), Some(10));
+        assert_eq!(stakes.stake_amount(&initialized_pubkey), None);
+        assert_eq!(stakes.stake_amount(&solana_sdk::pubkey::new_rand()), None);
+    }
+
+    #[test]
+    fn test_excluded_voters() {
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes_cache = StakesCache::new(
+            [
+                (vote_pubkey, vote_account),
+                (stake_pubkey, stake_account),
+                (vote_pubkey2, vote_account2),
+                (stake_pubkey2, stake_account2),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        stakes_cache.set_excluded_voters(HashSet::from([vote_pubkey]));
+        assert_eq!(stakes_cache.delegated_stake(&vote_pubkey), 0);
+        assert_eq!(stakes_cache.delegated_stake(&vote_pubkey2), 20);
+        assert_eq!(stakes_cache.total_stake(), 20);
+
+        // more stake delegated to the excluded voter is not reported either,
+        // while the cached stakes still account for it
+        let stake_pubkey3 = solana_sdk::pubkey::new_rand();
+        let stake_account3 = create_stake_account(30, &vote_pubkey, &stake_pubkey3);
+        stakes_cache.check_and_store(&stake_pubkey3, &stake_account3, 0, None);
+        stakes_cache.activate_epoch(1, &thread_pool, None);
+        check(&stakes_cache.stakes());
+        assert_eq!(stakes_cache.delegated_stake(&vote_pubkey), 0);
+        assert_eq!(stakes_cache.total_stake(), 20);
+        assert_eq!(
+            stakes_cache
+                .stakes()
+                .vote_accounts()
+                .get_delegated_stake(&vote_pubkey),
+            40
+        );
+
+        // the excluded voters are carried over to child banks
+        let child_stakes_cache = StakesCache::new_from_parent(&stakes_cache);
+        assert_eq!(child_stakes_cache.delegated_stake(&vote_pubkey), 0);
+        assert_eq!(child_stakes_cache.total_stake(), 20);
+
+        child_stakes_cache.set_excluded_voters(HashSet::default());
+        assert_eq!(child_stakes_cache.delegated_stake(&vote_pubkey), 40);
+        assert_eq!(child_stakes_cache.total_stake(), 60);
+        assert_eq!(stakes_cache.total_stake(), 20);
+    }
+
+    #[test]
+    fn test_active_delegation_count() {
+        let stakes_cache = StakesCache::default();
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(20, &vote_pubkey, &stake_pubkey2);
+        let initialized_account = stake_state::create_lockup_stake_account(
+            &stake::state::Authorized::auto(&stake_pubkey),
+            &stake::state::Lockup::default(),
+            &Rent::free(),
+            10,
+        );
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        stakes_cache.check_and_store(&stake_pubkey2, &stake_account2, 0, None);
+        stakes_cache.check_and_store(
+            &solana_sdk::pubkey::new_rand(),
+            &initialized_account,
+            0,
+            None,
+        );
+        assert_eq!(stakes_cache.stakes().active_delegation_count(), 2);
+        check(&stakes_cache.stakes());
+
+        // no longer delegated
+        stakes_cache.check_and_store(&stake_pubkey, &initialized_account, 0, None);
+        assert_eq!(stakes_cache.stakes().active_delegation_count(), 1);
+        check(&stakes_cache.stakes());
+        // removed
+        let mut stake_account2 = stake_account2;
+        stake_account2.set_lamports(0);
+        stakes_cache.check_and_store(&stake_pubkey2, &stake_account2, 0, None);
+        assert_eq!(stakes_cache.stakes().active_delegation_count(), 0);
+        assert_eq!(stakes_cache.undelegated_stake_accounts().len(), 2);
+        check(&stakes_cache.stakes());
+    }
+
+    #[test]
+    fn test_stake_timeline() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_warming_staked_node_accounts(1_000, 4);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let mut stake_history = StakeHistory::default();
+        for (epoch, effective, activating) in [(4, 1_000, 1_000), (5, 1_250, 750)] {
+            let entry = solana_sdk::stake_history::StakeHistoryEntry {
+                effective,
+                activating,
+                deactivating: 0,
+            };
+            stake_history.add(epoch, entry);
+        }
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        stakes.set_stake_history(stake_history, &thread_pool, None);
+        check(&stakes);
+
+        assert_eq!(
+            stakes.stake_timeline(&stake_pubkey, 3, 6, None),
+            vec![(3, 0), (4, 0), (5, 250), (6, 562)]
+        );
+        assert!(stakes
+            .stake_timeline(&solana_sdk::pubkey::new_rand(), 3, 6, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_merge_stake_accounts() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(20, &vote_pubkey, &stake_pubkey2);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(30);
+        let (warming_pubkey, warming_account) = create_warming_stake_account(5, 3, &vote_pubkey);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey3, vote_account3),
+            (stake_pubkey3, stake_account3),
+            (warming_pubkey, warming_account),
+        ]
+        .into_iter()
+        .collect();
+
+        stakes
+            .merge_stake_accounts(&stake_pubkey, &stake_pubkey2, None)
+            .unwrap();
+        let merged = &stakes.stake_delegations()[&stake_pubkey];
+        assert_eq!(merged.delegation().stake, 30);
+        assert_eq!(merged.lamports(), 30);
+        assert!(stakes.stake_delegations().get(&stake_pubkey2).is_none());
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 30);
+        check(&stakes);
+
+        // delegated to different voters
+        assert!(matches!(
+            stakes.merge_stake_accounts(&stake_pubkey, &stake_pubkey3, None),
+            Err(Error::MergeVoterMismatch {
+                source_voter,
+                destination_voter,
+            }) if source_voter == vote_pubkey3 && destination_voter == vote_pubkey
+        ));
+        assert!(matches!(
+            stakes.merge_stake_accounts(&stake_pubkey, &stake_pubkey2, None),
+            Err(Error::StakeAccountNotFound(pubkey)) if pubkey == stake_pubkey2
+        ));
+        // activating at a different epoch
+        assert!(matches!(
+            stakes.merge_stake_accounts(&stake_pubkey, &warming_pubkey, None),
+            Err(Error::MergeIncompatible {
+                source_account,
+                destination_account,
+            }) if source_account == warming_pubkey && destination_account == stake_pubkey
+        ));
+        assert!(matches!(
+            stakes.merge_stake_accounts(&stake_pubkey, &stake_pubkey, None),
+            Err(Error::MergeSelf(pubkey)) if pubkey == stake_pubkey
+        ));
+        assert_eq!(stakes.stake_delegations()[&stake_pubkey].lamports(), 30);
+        assert!(stakes.stake_delegations().get(&warming_pubkey).is_some());
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 30);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey3),
+            30
+        );
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_split_stake_account() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(30);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let new_pubkey = solana_sdk::pubkey::new_rand();
+
+        stakes
+            .split_stake_account(&stake_pubkey, &new_pubkey, 10, None)
+            .unwrap();
+        let remaining = &stakes.stake_delegations()[&stake_pubkey];
+        assert_eq!(remaining.delegation().stake, 20);
+        assert_eq!(remaining.lamports(), 20);
+        let split = &stakes.stake_delegations()[&new_pubkey];
+        assert_eq!(split.delegation().stake, 10);
+        assert_eq!(split.lamports(), 10);
+        assert_eq!(split.delegation().voter_pubkey, vote_pubkey);
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 30);
+        check(&stakes);
+
+        // splitting more than the source holds
+        let other_pubkey = solana_sdk::pubkey::new_rand();
+        assert!(matches!(
+            stakes.split_stake_account(&stake_pubkey, &other_pubkey, 21, None),
+            Err(Error::InvalidDelegation(pubkey)) if pubkey == stake_pubkey
+        ));
+        assert!(stakes.stake_delegations().get(&other_pubkey).is_none());
+        assert_eq!(
+            stakes.stake_delegations()[&stake_pubkey].delegation().stake,
+            20
+        );
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 30);
+
+        // splitting off no lamports
+        assert!(matches!(
+            stakes.split_stake_account(&stake_pubkey, &other_pubkey, 0, None),
+            Err(Error::InvalidDelegation(pubkey)) if pubkey == stake_pubkey
+        ));
+        assert!(stakes.stake_delegations().get(&other_pubkey).is_none());
+        assert_eq!(stakes.stake_delegations()[&stake_pubkey].lamports(), 20);
+        check(&stakes);
+
+        // splitting off all the lamports would leave the source empty
+        assert!(matches!(
+            stakes.split_stake_account(&stake_pubkey, &other_pubkey, 20, None),
+            Err(Error::SplitBelowRentExemptReserve(pubkey)) if pubkey == stake_pubkey
+        ));
+        assert!(stakes.stake_delegations().get(&other_pubkey).is_none());
+        assert_eq!(stakes.stake_delegations()[&stake_pubkey].lamports(), 20);
+
+        // both accounts keep the rent exempt reserve
+        let rent = Rent::default();
+        let rent_exempt_reserve = rent.minimum_balance(StakeStateV2::size_of());
+        let funded_pubkey = solana_sdk::pubkey::new_rand();
+        let mut funded_account = stake_state::create_account(
+            &funded_pubkey,
+            &vote_pubkey,
+            &vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1),
+            &rent,
+            rent_exempt_reserve + 100,
+        );
+        // funds the rent exempt reserve of the split off account
+        funded_account.set_lamports(2 * rent_exempt_reserve + 100);
+        stakes.check_and_store(&funded_pubkey, &funded_account, None);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            130
+        );
+        stakes
+            .split_stake_account(&funded_pubkey, &other_pubkey, 40, None)
+            .unwrap();
+        let remaining = &stakes.stake_delegations()[&funded_pubkey];
+        assert_eq!(remaining.delegation().stake, 60);
+        assert_eq!(remaining.lamports(), rent_exempt_reserve + 60);
+        let split = &stakes.stake_delegations()[&other_pubkey];
+        assert_eq!(split.delegation().stake, 40);
+        assert_eq!(split.lamports(), rent_exempt_reserve + 40);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            130
+        );
+        check(&stakes);
+
+        // the source can no longer pay for another rent exempt reserve
+        let another_pubkey = solana_sdk::pubkey::new_rand();
+        assert!(matches!(
+            stakes.split_stake_account(&funded_pubkey, &another_pubkey, 10, None),
+            Err(Error::SplitBelowRentExemptReserve(pubkey)) if pubkey == funded_pubkey
+        ));
+        assert!(stakes.stake_delegations().get(&another_pubkey).is_none());
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            130
+        );
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_voter_churn() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(30);
+        let previous: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let mut stakes = previous.clone();
+        // vote_pubkey1 exits the staked set, though its vote account stays.
+        stakes.remove_stake_delegation(&stake_pubkey1, None);
+        // vote_pubkey2 persists with a changed stake.
+        let stake_pubkey4 = solana_sdk::pubkey::new_rand();
+        stakes.upsert_stake_delegation(
+            stake_pubkey4,
+            StakeAccount::try_from(create_stake_account(5, &vote_pubkey2, &stake_pubkey4)).unwrap(),
+            None,
+        );
+        // vote_pubkey3 enters the staked set.
+        stakes.extend([
+            (vote_pubkey3, vote_account3),
+            (stake_pubkey3, stake_account3),
+        ]);
+
+        assert!(stakes.vote_accounts().get(&vote_pubkey1).is_some());
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            25
+        );
+        check(&stakes);
+        assert_eq!(
+            stakes.voter_churn(&previous),
+            (vec![vote_pubkey3], vec![vote_pubkey1])
+        );
+        assert_eq!(
+            previous.voter_churn(&stakes),
+            (vec![vote_pubkey1], vec![vote_pubkey3])
+        );
+        assert_eq!(stakes.voter_churn(&stakes), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_observe_supermajority_change() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(50);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(30);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1.clone()),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey3, vote_account3),
+            (stake_pubkey3, stake_account3),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            stakes.supermajority_set(),
+            HashSet::from([vote_pubkey1, vote_pubkey2])
+        );
+        let stakes_cache = Arc::new(StakesCache::new(stakes));
+        let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        stakes_cache.observe_supermajority_change({
+            let changes = changes.clone();
+            let stakes_cache = Arc::downgrade(&stakes_cache);
+            move |change| {
+                // The lock on the stakes is released before notifying.
+                let stakes_cache = stakes_cache.upgrade().unwrap();
+                let voters = stakes_cache.stakes().supermajority_set();
+                changes.lock().unwrap().push((change.clone(), voters));
+            }
+        });
+
+        // vote_pubkey3 overtakes vote_pubkey2.
+        let stake_pubkey4 = solana_sdk::pubkey::new_rand();
+        let stake_account4 = create_stake_account(20, &vote_pubkey3, &stake_pubkey4);
+        stakes_cache.check_and_store(&stake_pubkey4, &stake_account4, 0, None);
+        check(&stakes_cache.stakes());
+        assert_eq!(
+            std::mem::take(&mut *changes.lock().unwrap()),
+            vec![(
+                SupermajorityChange {
+                    added: vec![vote_pubkey3],
+                    removed: vec![vote_pubkey2],
+                },
+                HashSet::from([vote_pubkey1, vote_pubkey3]),
+            )]
+        );
+
+        // stake changes which keep the membership are not reported
+        let stake_pubkey5 = solana_sdk::pubkey::new_rand();
+        let stake_account5 = create_stake_account(1, &vote_pubkey1, &stake_pubkey5);
+        stakes_cache.check_and_store(&stake_pubkey5, &stake_account5, 0, None);
+        stakes_cache.check_and_store(&vote_pubkey1, &vote_account1, 0, None);
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        stakes_cache.activate_epoch(1, &thread_pool, None);
+        check(&stakes_cache.stakes());
+        assert!(changes.lock().unwrap().is_empty());
+
+        // the observer is carried over to child banks
+        let child_stakes_cache = StakesCache::new_from_parent(&stakes_cache);
+        let stake_pubkey6 = solana_sdk::pubkey::new_rand();
+        let stake_account6 = create_stake_account(100, &vote_pubkey2, &stake_pubkey6);
+        child_stakes_cache.check_and_store(&stake_pubkey6, &stake_account6, 0, None);
+        check(&child_stakes_cache.stakes());
+        assert_eq!(
+            std::mem::take(&mut *changes.lock().unwrap())
+                .into_iter()
+                .map(|(change, _)| change)
+                .collect::<Vec<_>>(),
+            vec![SupermajorityChange {
+                added: vec![vote_pubkey2],
+                removed: vec![vote_pubkey3],
+            }]
+        );
+        assert_eq!(
+            child_stakes_cache.stakes().supermajority_set(),
+            HashSet::from([vote_pubkey1, vote_pubkey2])
+        );
+        // ... but the parent keeps its own supermajority set.
+        assert_eq!(
+            stakes_cache.stakes().supermajority_set(),
+            HashSet::from([vote_pubkey1, vote_pubkey3])
+        );
+    }
+
+    #[test]
+    fn test_estimate_reward_share() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(stakes.estimate_reward_share(&vote_pubkey1, 900), 0);
+        stakes.extend([
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]);
+        assert_eq!(stakes.estimate_reward_share(&vote_pubkey1, 900), 300);
+        assert_eq!(stakes.estimate_reward_share(&vote_pubkey2, 900), 600);
+        assert_eq!(stakes.estimate_reward_share(&vote_pubkey1, 1000), 333);
+        assert_eq!(
+            stakes.estimate_reward_share(&vote_pubkey2, u64::MAX),
+            u64::MAX / 3 * 2
+        );
+        assert_eq!(
+            stakes.estimate_reward_share(&solana_sdk::pubkey::new_rand(), 900),
+            0
+        );
+    }
+
+    #[test]
+    fn test_iter_vote_accounts() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1.clone()),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2.clone()),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let vote_accounts: Vec<_> = stakes
+            .iter_vote_accounts()
+            .map(|(vote_pubkey, stake, vote_account)| {
+                (vote_pubkey, stake, vote_account.account().clone())
+            })
+            .sorted_unstable_by_key(|(_, stake, _)| *stake)
+            .collect();
+        assert_eq!(
+            vote_accounts,
+            vec![
+                (vote_pubkey1, 10, vote_account1),
+                (vote_pubkey2, 20, vote_account2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stakes_legacy_vote_state_versions() {
+        let node_pubkey = solana_sdk::pubkey::new_rand();
+        let authorized_voter = solana_sdk::pubkey::new_rand();
+        let authorized_withdrawer = solana_sdk::pubkey::new_rand();
+        let commission = 7;
+        let mut stakes = Stakes::<StakeAccount>::default();
+
+        // V1_14_11
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_state = VoteState::new(
+            &vote_state::VoteInit {
+                node_pubkey,
+                authorized_voter,
+                authorized_withdrawer,
+                commission,
+            },
+            &solana_sdk::clock::Clock::default(),
+        );
+        let versioned =
+            VoteStateVersions::V1_14_11(Box::new(vote_state::VoteState1_14_11::from(vote_state)));
+        let mut vote_account = AccountSharedData::new(
+            1,
+            vote_state::VoteState1_14_11::size_of(),
+            &solana_vote_program::id(),
+        );
+        vote_state::to(&versioned, &mut vote_account).unwrap();
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        let cached = stakes.vote_accounts().get(&vote_pubkey).unwrap();
+        assert_eq!(cached.node_pubkey(), &node_pubkey);
+        assert_eq!(cached.vote_state().commission, commission);
+
+        // V0_23_5, serialized field by field since its type is private to the
+        // sdk.
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let mut data = bincode::serialize(&(
+            0u32, // V0_23_5
+            node_pubkey,
+            authorized_voter,
+            0u64,                                                // authorized_voter_epoch
+            ([(Pubkey::default(), 0u64, 0u64, 0u64); 32], 0u64), // prior_voters
+            authorized_withdrawer,
+            commission,
+            Vec::<vote_state::Lockout>::new(), // votes
+            None::<Slot>,                      // root_slot
+            Vec::<(Epoch, u64, u64)>::new(),   // epoch_credits
+            (0u64, 0i64),                      // last_timestamp
+        ))
+        .unwrap();
+        data.resize(VoteState::size_of(), 0u8);
+        let mut vote_account = AccountSharedData::new(1, 0, &solana_vote_program::id());
+        vote_account.set_data(data);
+        // The cache keeps rejecting the legacy layout, but it can still be
+        // parsed outside the cache.
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        assert!(stakes.vote_accounts().get(&vote_pubkey).is_none());
+        let parsed = parse_vote_account(&vote_account).unwrap();
+        assert_eq!(parsed.node_pubkey(), &node_pubkey);
+        assert_eq!(parsed.vote_state().commission, commission);
+
+        // uninitialized V0_23_5
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            AccountSharedData::new(1, VoteState::size_of(), &solana_vote_program::id());
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        assert!(stakes.vote_accounts().get(&vote_pubkey).is_none());
+        assert!(parse_vote_account(&vote_account).is_none());
+    }
+
+    #[test]
+    fn test_orphan_delegations() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let missing_voter = solana_sdk::pubkey::new_rand();
+        let orphan_pubkey = solana_sdk::pubkey::new_rand();
+        let orphan_account = create_stake_account(20, &missing_voter, &orphan_pubkey);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account.clone()),
+            (stake_pubkey, stake_account),
+            (orphan_pubkey, orphan_account),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            stakes.orphan_delegations(),
+            vec![(orphan_pubkey, missing_voter, 20)]
+        );
+
+        // closing the vote account orphans its delegations too.
+        let mut vote_account = vote_account;
+        vote_account.set_lamports(0);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        check(&stakes);
+        assert_eq!(
+            stakes.orphan_delegations(),
+            [
+                (orphan_pubkey, missing_voter, 20),
+                (stake_pubkey, vote_pubkey, 10),
+            ]
+            .into_iter()
+            .sorted_unstable()
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        let mut vote_accounts: Vec<_> = (0..100)
+            .map(|_| {
+                let vote_pubkey = solana_sdk::pubkey::new_rand();
+                let vote_account =
+                    vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+                stakes.check_and_store(&vote_pubkey, &vote_account, None);
+                (vote_pubkey, vote_account)
+            })
+            .collect();
+        for (vote_pubkey, vote_account) in vote_accounts.iter_mut().skip(1) {
+            vote_account.set_lamports(0);
+            stakes.check_and_store(vote_pubkey, vote_account, None);
+        }
+        let capacity = stakes.vote_accounts().as_ref().capacity();
+
+        // The vote accounts are shared with the clone, so are not copied.
+        let shared = stakes.clone();
+        stakes.compact();
+        assert_eq!(stakes.vote_accounts().as_ref().capacity(), capacity);
+        assert!(std::ptr::eq(
+            stakes.vote_accounts().as_ref(),
+            shared.vote_accounts().as_ref()
+        ));
+        assert_eq!(stakes, shared);
+
+        drop(shared);
+        stakes.compact();
+        assert!(stakes.vote_accounts().as_ref().capacity() < capacity);
+        assert_eq!(stakes.vote_accounts().len(), 1);
+        assert!(stakes.vote_accounts().get(&vote_accounts[0].0).is_some());
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_vote_accounts_by_authorized_voter() {
+        let authorized_voter = solana_sdk::pubkey::new_rand();
+        let new_authorized_voter = solana_sdk::pubkey::new_rand();
+        let mut stakes = Stakes::<StakeAccount>::default();
+
+        // vote_pubkey switches to new_authorized_voter at epoch 2.
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let mut vote_state = VoteState::new(
+            &vote_state::VoteInit {
+                node_pubkey: solana_sdk::pubkey::new_rand(),
+                authorized_voter,
+                authorized_withdrawer: solana_sdk::pubkey::new_rand(),
+                commission: 0,
+            },
+            &solana_sdk::clock::Clock::default(),
+        );
+        vote_state
+            .set_new_authorized_voter(&new_authorized_voter, 0, 2, |_| Ok(()))
+            .unwrap();
+        let mut vote_account =
+            AccountSharedData::new(1, VoteState::size_of(), &solana_vote_program::id());
+        vote_state::to(
+            &VoteStateVersions::new_current(vote_state),
+            &mut vote_account,
+        )
+        .unwrap();
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+
+        // vote_pubkey2 keeps authorized_voter.
+        let vote_pubkey2 = solana_sdk::pubkey::new_rand();
+        let vote_account2 = vote_state::create_account_with_authorized(
+            &solana_sdk::pubkey::new_rand(),
+            &authorized_voter,
+            &solana_sdk::pubkey::new_rand(),
+            0,
+            1,
+        );
+        stakes.check_and_store(&vote_pubkey2, &vote_account2, None);
+
+        let both: Vec<_> = [vote_pubkey, vote_pubkey2]
+            .into_iter()
+            .sorted_unstable()
+            .collect();
+        for epoch in 0..2 {
+            assert_eq!(
+                stakes.vote_accounts_by_authorized_voter(&authorized_voter, epoch),
+                both
+            );
+            assert!(stakes
+                .vote_accounts_by_authorized_voter(&new_authorized_voter, epoch)
+                .is_empty());
+        }
+        for epoch in 2..4 {
+            assert_eq!(
+                stakes.vote_accounts_by_authorized_voter(&authorized_voter, epoch),
+                vec![vote_pubkey2]
+            );
+            assert_eq!(
+                stakes.vote_accounts_by_authorized_voter(&new_authorized_voter, epoch),
+                vec![vote_pubkey]
+            );
+        }
+    }
+
+    #[test]
+    fn test_bulk_remove() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stake_pubkey3 = solana_sdk::pubkey::new_rand();
+        let stake_account3 = create_stake_account(30, &vote_pubkey2, &stake_pubkey3);
+        let stake_pubkey4 = solana_sdk::pubkey::new_rand();
+        let stake_account4 = create_stake_account(40, &vote_pubkey2, &stake_pubkey4);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (stake_pubkey3, stake_account3),
+            (stake_pubkey4, stake_account4),
+        ]
+        .into_iter()
+        .collect();
+        let pubkeys = [
+            stake_pubkey1,
+            vote_pubkey1,
+            stake_pubkey2,
+            stake_pubkey3,
+            solana_sdk::pubkey::new_rand(),
+        ];
+
+        let mut expected = stakes.clone();
+        for pubkey in &pubkeys {
+            expected.remove_vote_account(pubkey);
+            expected.remove_stake_delegation(pubkey, None);
+        }
+        let mut stakes = stakes;
+        stakes.bulk_remove(&pubkeys, None);
+        assert_eq!(stakes, expected);
+        check(&stakes);
+        assert!(stakes.vote_accounts().get(&vote_pubkey1).is_none());
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            40
+        );
+        assert_eq!(
+            stakes.stake_delegations().keys().collect::<Vec<_>>(),
+            vec![&stake_pubkey4]
+        );
+    }
+
+    #[test]
+    fn test_delegation_edges() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stake_pubkey3 = solana_sdk::pubkey::new_rand();
+        let stake_account3 = create_stake_account(30, &vote_pubkey1, &stake_pubkey3);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (stake_pubkey3, stake_account3),
+        ]
+        .into_iter()
+        .collect();
+        let mut expected = vec![
+            (stake_pubkey1, vote_pubkey1, 10),
+            (stake_pubkey2, vote_pubkey2, 20),
+            (stake_pubkey3, vote_pubkey1, 30),
+        ];
+        expected.sort_unstable_by_key(|(stake_pubkey, _, _)| *stake_pubkey);
+        assert_eq!(stakes.delegation_edges(), expected);
+        assert_eq!(
+            stakes
+                .delegations_iter()
+                .sorted_unstable()
+                .collect::<Vec<_>>(),
+            stakes.delegation_edges()
+        );
+        assert_eq!(
+            stakes
+                .delegations_iter()
+                .find(|(_, voter_pubkey, _)| voter_pubkey == &vote_pubkey2),
+            Some((stake_pubkey2, vote_pubkey2, 20))
+        );
+    }
+
+    #[test]
+    fn test_force_set_stake() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let vote_pubkey2 = solana_sdk::pubkey::new_rand();
+        let vote_pubkey3 = solana_sdk::pubkey::new_rand();
+        stakes.force_set_stake(&vote_pubkey, 50);
+        stakes.force_set_stake(&vote_pubkey2, 30);
+        stakes.force_set_stake(&vote_pubkey3, 40);
+        stakes.force_set_stake(&vote_pubkey3, 20);
+
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 50);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            30
+        );
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey3),
+            20
+        );
+        assert_eq!(stakes.total_stake(), 100);
+        assert_eq!(
+            stakes.supermajority_set(),
+            HashSet::from([vote_pubkey, vote_pubkey2])
+        );
+        assert_eq!(stakes.estimate_reward_share(&vote_pubkey3, 100), 20);
+        assert_eq!(
+            stakes.vote_accounts().staked_nodes().get(&vote_pubkey2),
+            Some(&30)
+        );
+        // Only the stake account of vote_pubkey backs any stake.
+        assert_eq!(stakes.stake_delegations().len(), 1);
+    }
+
+    #[test]
+    fn test_warmup_gap() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(1_000);
+        let (warming_pubkey, warming_account) =
+            create_warming_stake_account(1_000, 0, &vote_pubkey);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (warming_pubkey, warming_account),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stakes.warmup_gap(), 1_000);
+
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let mut warmup_gap = stakes.warmup_gap();
+        for epoch in 1..10 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+            assert!(stakes.warmup_gap() < warmup_gap || stakes.warmup_gap() == 0);
+            assert_eq!(stakes.warmup_gap() + stakes.total_stake(), 2_000);
+            check(&stakes);
+            warmup_gap = stakes.warmup_gap();
+        }
+        assert_eq!(warmup_gap, 0);
+    }
+
+    #[test]
+    fn test_delegator_counts() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(10);
+        let vote_pubkey3 = solana_sdk::pubkey::new_rand();
+        let vote_account3 =
+            vote_state::create_account(&vote_pubkey3, &solana_sdk::pubkey::new_rand(), 0, 1);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey3, vote_account3),
+        ]
+        .into_iter()
+        .collect();
+        for _ in 0..2 {
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(5, &vote_pubkey2, &stake_pubkey);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        }
+        // delegated to a vote account which is not cached
+        let stake_pubkey = solana_sdk::pubkey::new_rand();
+        let stake_account = create_stake_account(5, &solana_sdk::pubkey::new_rand(), &stake_pubkey);
+        stakes.check_and_store(&stake_pubkey, &stake_account, None);
+
+        assert_eq!(
+            stakes.delegator_counts(),
+            HashMap::from([(vote_pubkey1, 1), (vote_pubkey2, 3), (vote_pubkey3, 0)])
+        );
+    }
+
+    #[test]
+    fn test_largest_delegation() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(30, &vote_pubkey, &stake_pubkey2);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(50);
+        let vote_pubkey4 = solana_sdk::pubkey::new_rand();
+        let vote_account4 =
+            vote_state::create_account(&vote_pubkey4, &solana_sdk::pubkey::new_rand(), 0, 1);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey3, vote_account3),
+            (stake_pubkey3, stake_account3),
+            (vote_pubkey4, vote_account4),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            stakes.largest_delegation(&vote_pubkey),
+            Some((stake_pubkey2, 30))
+        );
+        assert_eq!(
+            stakes.largest_delegation(&vote_pubkey3),
+            Some((stake_pubkey3, 50))
+        );
+        assert_eq!(stakes.largest_delegation(&vote_pubkey4), None);
+    }
+
+    #[test]
+    fn test_activating_stakes() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(1_000);
+        let (warming_pubkey, warming_account) =
+            create_warming_stake_account(1_000, 0, &vote_pubkey);
+        let (pending_pubkey, pending_account) = create_warming_stake_account(500, 5, &vote_pubkey);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account),
+            (warming_pubkey, warming_account),
+            (pending_pubkey, pending_account),
+        ]
+        .into_iter()
+        .collect();
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        stakes.activate_epoch(1, &thread_pool, None);
+        let expected: Vec<_> = [(warming_pubkey, 1_000, 0), (pending_pubkey, 500, 5)]
+            .into_iter()
+            .sorted_unstable()
+            .collect();
+        assert_eq!(stakes.activating_stakes(1, None), expected);
+
+        for epoch in 2..5 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+        }
+        check(&stakes);
+        assert_eq!(
+            stakes.activating_stakes(4, None),
+            vec![(pending_pubkey, 500, 5)]
+        );
+
+        for epoch in 5..8 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+        }
+        assert!(stakes.activating_stakes(7, None).is_empty());
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_total_stake_excluding() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(30);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey3, vote_account3),
+            (stake_pubkey3, stake_account3),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stakes.total_stake_excluding(&HashSet::default()), 60);
+        assert_eq!(
+            stakes.total_stake_excluding(&HashSet::from([
+                vote_pubkey2,
+                solana_sdk::pubkey::new_rand()
+            ])),
+            40
+        );
+    }
+
+    #[test]
+    fn test_voter_stake_histogram() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        for stake in [5, 10, 15, 20, 100, 1_000] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        }
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+
+        assert_eq!(
+            stakes.voter_stake_histogram(&[10, 20, 100], false),
+            vec![1, 2, 1, 2]
+        );
+        assert_eq!(
+            stakes.voter_stake_histogram(&[10, 20, 100], true),
+            vec![2, 2, 1, 2]
+        );
+        assert_eq!(stakes.voter_stake_histogram(&[], false), vec![6]);
+    }
+
+    #[test]
+    fn test_vote_account_cloned() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account.clone()),
+            (stake_pubkey, stake_account),
+        ]
+        .into_iter()
+        .collect();
+        let (stake, mut account) = stakes.vote_account_cloned(&vote_pubkey).unwrap();
+        assert_eq!(stake, 10);
+        assert_eq!(account, vote_account);
+
+        account.set_lamports(42);
+        account.data_as_mut_slice().fill(0);
+        let cached = stakes.vote_accounts().get(&vote_pubkey).unwrap();
+        assert_eq!(cached.account(), &vote_account);
+        assert_eq!(
+            stakes.vote_account_cloned(&vote_pubkey),
+            Some((10, vote_account))
+        );
+        assert_eq!(
+            stakes.vote_account_cloned(&solana_sdk::pubkey::new_rand()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_check_bounds() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stakes.check_bounds(), Ok(()));
+
+        // a stake which wrapped around
+        stakes.force_set_stake(&vote_pubkey2, u64::MAX - 5);
+        assert_eq!(stakes.check_bounds(), Err(vec![vote_pubkey2]));
+        stakes.force_set_stake(&vote_pubkey2, 20);
+        assert_eq!(stakes.check_bounds(), Ok(()));
+    }
+
+    #[test]
+    fn test_apply_changes() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(30);
+        let redelegated_account = create_stake_account(20, &vote_pubkey3, &stake_pubkey2);
+        let changes = vec![
+            (vote_pubkey3, Some(vote_account3)),
+            (stake_pubkey3, Some(stake_account3)),
+            (stake_pubkey1, None),
+            (stake_pubkey2, Some(redelegated_account)),
+            (vote_pubkey1, None),
+            (
+                stake_pubkey1,
+                Some(create_stake_account(5, &vote_pubkey2, &stake_pubkey1)),
+            ),
+        ];
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use {
-        super::*,
-        rayon::ThreadPoolBuilder,
-        solana_sdk::{account::WritableAccount, pubkey::Pubkey, rent::Rent, stake},
-        solana_stake_program::stake_state,
-        solana_vote_program::vote_state::{self, VoteState, VoteStateVersions},
-    };
+        let mut expected = stakes.clone();
+        for (pubkey, account) in changes.clone() {
+            expected.store_or_remove(&pubkey, account.as_ref(), None);
+        }
+        let mut stakes = stakes;
+        stakes.apply_changes(changes, None);
+        assert_eq!(stakes, expected);
+        check(&stakes);
+        assert!(stakes.vote_accounts().get(&vote_pubkey1).is_none());
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey2), 5);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey3),
+            50
+        );
+        assert!(stakes.accounting_drift(None).is_empty());
+    }
 
-    //  set up some dummies for a staked node     ((     vote      )  (     stake     ))
-    pub(crate) fn create_staked_node_accounts(
-        stake: u64,
-    ) -> ((Pubkey, AccountSharedData), (Pubkey, AccountSharedData)) {
+    #[test]
+    fn test_stake_stddev() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(stakes.stake_stddev(), 0.0);
+        for stake in [2, 4, 4, 4, 5, 5, 7, 9] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            if stake == 2 {
+                assert_eq!(stakes.stake_stddev(), 0.0);
+            }
+        }
+        // unstaked vote accounts are ignored
         let vote_pubkey = solana_sdk::pubkey::new_rand();
         let vote_account =
             vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
-        let stake_pubkey = solana_sdk::pubkey::new_rand();
-        (
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        // mean is 5, and the squared deviations sum up to 32.
+        assert!((stakes.stake_stddev() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_fully_active_delegations() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(1_000);
+        let (early_pubkey, early_account) = create_warming_stake_account(100, 0, &vote_pubkey);
+        let (late_pubkey, late_account) = create_warming_stake_account(100, 3, &vote_pubkey);
+        let mut stakes: Stakes<StakeAccount> = [
             (vote_pubkey, vote_account),
-            (
-                stake_pubkey,
-                create_stake_account(stake, &vote_pubkey, &stake_pubkey),
-            ),
-        )
+            (stake_pubkey, stake_account),
+            (early_pubkey, early_account),
+            (late_pubkey, late_account),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stakes.fully_active_delegations(0, None), vec![stake_pubkey]);
+
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        for epoch in 1..4 {
+            stakes.activate_epoch(epoch, &thread_pool, None);
+        }
+        // 100 of 1_000 effective lamports warm up within one epoch.
+        let expected: Vec<_> = [stake_pubkey, early_pubkey]
+            .into_iter()
+            .sorted_unstable()
+            .collect();
+        assert_eq!(stakes.fully_active_delegations(1, None), expected);
+        assert_eq!(stakes.fully_active_delegations(3, None), expected);
+
+        stakes.activate_epoch(4, &thread_pool, None);
+        let expected: Vec<_> = [stake_pubkey, early_pubkey, late_pubkey]
+            .into_iter()
+            .sorted_unstable()
+            .collect();
+        assert_eq!(stakes.fully_active_delegations(4, None), expected);
     }
 
-    //   add stake to a vote_pubkey                               (   stake    )
-    pub(crate) fn create_stake_account(
-        stake: u64,
-        vote_pubkey: &Pubkey,
-        stake_pubkey: &Pubkey,
-    ) -> AccountSharedData {
-        stake_state::create_account(
-            stake_pubkey,
-            vote_pubkey,
-            &vote_state::create_account(vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1),
-            &Rent::free(),
-            stake,
-        )
+    #[test]
+    fn test_equal_ignoring_epoch() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let mut other = stakes.clone();
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        other.activate_epoch(1, &thread_pool, None);
+        assert_ne!(stakes, other);
+        assert!(stakes.equal_ignoring_epoch(&other));
+
+        other.force_set_stake(&vote_pubkey, 11);
+        assert!(!stakes.equal_ignoring_epoch(&other));
     }
 
-    fn create_warming_staked_node_accounts(
-        stake: u64,
-        epoch: Epoch,
-    ) -> ((Pubkey, AccountSharedData), (Pubkey, AccountSharedData)) {
+    #[test]
+    fn test_write_vote_accounts_json() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        let mut expected = Vec::new();
+        for (stake, commission) in [(10, 5), (20, 100)] {
+            let vote_pubkey = solana_sdk::pubkey::new_rand();
+            let node_pubkey = solana_sdk::pubkey::new_rand();
+            let vote_account =
+                vote_state::create_account(&vote_pubkey, &node_pubkey, commission, 1);
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            expected.push((
+                vote_pubkey,
+                serde_json::json!({
+                    "voter": vote_pubkey.to_string(),
+                    "stake": stake,
+                    "node_identity": node_pubkey.to_string(),
+                    "commission": commission,
+                }),
+            ));
+        }
+        // unstaked vote accounts are not written
         let vote_pubkey = solana_sdk::pubkey::new_rand();
         let vote_account =
             vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
-        (
-            (vote_pubkey, vote_account),
-            create_warming_stake_account(stake, epoch, &vote_pubkey),
-        )
-    }
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        expected.sort_unstable_by_key(|(vote_pubkey, _)| *vote_pubkey);
+        let expected = expected.into_iter().map(|(_, vote_account)| vote_account);
 
-    // add stake to a vote_pubkey                               (   stake    )
-    fn create_warming_stake_account(
-        stake: u64,
-        epoch: Epoch,
-        vote_pubkey: &Pubkey,
-    ) -> (Pubkey, AccountSharedData) {
-        let stake_pubkey = solana_sdk::pubkey::new_rand();
-        (
-            stake_pubkey,
-            stake_state::create_account_with_activation_epoch(
-                &stake_pubkey,
-                vote_pubkey,
-                &vote_state::create_account(vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1),
-                &Rent::free(),
-                stake,
-                epoch,
-            ),
-        )
+        let mut buffer = Vec::new();
+        stakes.write_vote_accounts_json(&mut buffer).unwrap();
+        let vote_accounts: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(vote_accounts, serde_json::Value::Array(expected.collect()));
+
+        let mut buffer = Vec::new();
+        Stakes::<StakeAccount>::default()
+            .write_vote_accounts_json(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, b"[]");
     }
 
     #[test]
-    fn test_stakes_basic() {
-        for i in 0..4 {
-            let stakes_cache = StakesCache::new(Stakes {
-                epoch: i,
-                ..Stakes::default()
-            });
-
-            let ((vote_pubkey, vote_account), (stake_pubkey, mut stake_account)) =
-                create_staked_node_accounts(10);
+    fn test_prune_orphan_delegations() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let orphan_pubkey = solana_sdk::pubkey::new_rand();
+        let orphan_account =
+            create_stake_account(30, &solana_sdk::pubkey::new_rand(), &orphan_pubkey);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2.clone()),
+            (stake_pubkey2, stake_account2),
+            (orphan_pubkey, orphan_account),
+        ]
+        .into_iter()
+        .collect();
+        // closing vote_pubkey2 orphans stake_pubkey2
+        let mut closed_vote_account2 = vote_account2.clone();
+        closed_vote_account2.set_lamports(0);
+        stakes.check_and_store(&vote_pubkey2, &closed_vote_account2, None);
+        assert_eq!(stakes.total_stake(), 10);
 
-            stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-            stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
-            let stake = stake_state::stake_from(&stake_account).unwrap();
-            {
-                let stakes = stakes_cache.stakes();
-                let vote_accounts = stakes.vote_accounts();
-                assert!(vote_accounts.get(&vote_pubkey).is_some());
-                assert_eq!(
-                    vote_accounts.get_delegated_stake(&vote_pubkey),
-                    stake.stake(i, &StakeHistory::default(), None)
-                );
-            }
+        assert_eq!(stakes.prune_orphan_delegations(), 2);
+        check(&stakes);
+        assert_eq!(stakes.total_stake(), 10);
+        // the orphan delegations are kept, same as their stake accounts.
+        assert_eq!(stakes.orphan_delegations().len(), 2);
+        assert_eq!(stakes.stake_delegations().len(), 3);
 
-            stake_account.set_lamports(42);
-            stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
-            {
-                let stakes = stakes_cache.stakes();
-                let vote_accounts = stakes.vote_accounts();
-                assert!(vote_accounts.get(&vote_pubkey).is_some());
-                assert_eq!(
-                    vote_accounts.get_delegated_stake(&vote_pubkey),
-                    stake.stake(i, &StakeHistory::default(), None)
-                ); // stays old stake, because only 10 is activated
-            }
+        // caching the vote account again counts its stake again.
+        stakes.check_and_store(&vote_pubkey2, &vote_account2, None);
+        check(&stakes);
+        assert_eq!(stakes.total_stake(), 30);
+        assert_eq!(stakes.prune_orphan_delegations(), 1);
+        assert_eq!(stakes.total_stake(), 30);
+    }
 
-            // activate more
-            let mut stake_account =
-                create_stake_account(42, &vote_pubkey, &solana_sdk::pubkey::new_rand());
-            stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
-            let stake = stake_state::stake_from(&stake_account).unwrap();
-            {
-                let stakes = stakes_cache.stakes();
-                let vote_accounts = stakes.vote_accounts();
-                assert!(vote_accounts.get(&vote_pubkey).is_some());
-                assert_eq!(
-                    vote_accounts.get_delegated_stake(&vote_pubkey),
-                    stake.stake(i, &StakeHistory::default(), None)
-                ); // now stake of 42 is activated
-            }
+    #[test]
+    fn test_stake_weighted_mean_commission() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(stakes.stake_weighted_mean_commission(), 0.0);
+        for (stake, commission) in [(10, 10), (30, 50)] {
+            let vote_pubkey = solana_sdk::pubkey::new_rand();
+            let vote_account = vote_state::create_account(
+                &vote_pubkey,
+                &solana_sdk::pubkey::new_rand(),
+                commission,
+                1,
+            );
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        }
+        // unstaked vote accounts carry no weight
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 100, 1);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        // (10 * 10 + 30 * 50) / 40
+        assert!((stakes.stake_weighted_mean_commission() - 40.0).abs() < 1e-9);
+    }
 
-            stake_account.set_lamports(0);
-            stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
-            {
-                let stakes = stakes_cache.stakes();
-                let vote_accounts = stakes.vote_accounts();
-                assert!(vote_accounts.get(&vote_pubkey).is_some());
-                assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+    #[test]
+    fn test_weighted_stake() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let weighted_stake = stakes.weighted_stake(|vote_pubkey| {
+            if vote_pubkey == &vote_pubkey2 {
+                0.5
+            } else {
+                1.0
             }
-        }
+        });
+        assert_eq!(
+            weighted_stake,
+            HashMap::from([(vote_pubkey1, 10.0), (vote_pubkey2, 10.0)])
+        );
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            20
+        );
     }
 
     #[test]
-    fn test_stakes_highest() {
-        let stakes_cache = StakesCache::default();
+    fn test_store_returning_previous() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(
+            stakes.store_returning_previous(&vote_pubkey, &vote_account, None),
+            None
+        );
+        assert_eq!(
+            stakes.store_returning_previous(&stake_pubkey, &stake_account, None),
+            None
+        );
+        let new_stake_account = create_stake_account(20, &vote_pubkey, &stake_pubkey);
+        assert_eq!(
+            stakes.store_returning_previous(&stake_pubkey, &new_stake_account, None),
+            Some(stake_account)
+        );
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 20);
+        check(&stakes);
 
-        assert_eq!(stakes_cache.stakes().highest_staked_node(), None);
+        let mut closed_vote_account = vote_account.clone();
+        closed_vote_account.set_lamports(0);
+        assert_eq!(
+            stakes.store_returning_previous(&vote_pubkey, &closed_vote_account, None),
+            Some(vote_account)
+        );
+        assert!(stakes.vote_accounts().get(&vote_pubkey).is_none());
+    }
 
-        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+    #[test]
+    fn test_sorted_by_delegator_count() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(100);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
             create_staked_node_accounts(10);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        for _ in 0..2 {
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(10, &vote_pubkey2, &stake_pubkey);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        }
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+        assert_eq!(
+            stakes.sorted_by_delegator_count(),
+            vec![(vote_pubkey2, 3), (vote_pubkey1, 1)]
+        );
+        assert_eq!(
+            stakes.voters_in_stake_range(0, u64::MAX),
+            vec![(vote_pubkey1, 100), (vote_pubkey2, 30)]
+        );
+    }
 
-        let ((vote11_pubkey, vote11_account), (stake11_pubkey, stake11_account)) =
+    #[test]
+    fn test_delegation_changes() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
             create_staked_node_accounts(20);
+        let stake_pubkey3 = solana_sdk::pubkey::new_rand();
+        let stake_account3 = create_stake_account(30, &vote_pubkey1, &stake_pubkey3);
+        let unchanged_pubkey = solana_sdk::pubkey::new_rand();
+        let unchanged_account = create_stake_account(40, &vote_pubkey2, &unchanged_pubkey);
+        let previous: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+            (stake_pubkey3, stake_account3.clone()),
+            (unchanged_pubkey, unchanged_account),
+        ]
+        .into_iter()
+        .collect();
+        assert!(previous.delegation_changes(&previous).is_empty());
 
-        stakes_cache.check_and_store(&vote11_pubkey, &vote11_account, None);
-        stakes_cache.check_and_store(&stake11_pubkey, &stake11_account, None);
-
-        let vote11_node_pubkey = vote_state::from(&vote11_account).unwrap().node_pubkey;
+        let mut stakes = previous.clone();
+        // stake_pubkey1 is redelegated, stake_pubkey2 changes stake,
+        // stake_pubkey3 is removed and stake_pubkey4 is added.
+        let stake_account1 = create_stake_account(10, &vote_pubkey2, &stake_pubkey1);
+        stakes.check_and_store(&stake_pubkey1, &stake_account1, None);
+        let stake_account2 = create_stake_account(25, &vote_pubkey2, &stake_pubkey2);
+        stakes.check_and_store(&stake_pubkey2, &stake_account2, None);
+        let mut stake_account3 = stake_account3;
+        stake_account3.set_lamports(0);
+        stakes.check_and_store(&stake_pubkey3, &stake_account3, None);
+        let stake_pubkey4 = solana_sdk::pubkey::new_rand();
+        let stake_account4 = create_stake_account(50, &vote_pubkey1, &stake_pubkey4);
+        stakes.check_and_store(&stake_pubkey4, &stake_account4, None);
+        check(&stakes);
 
-        let highest_staked_node = stakes_cache.stakes().highest_staked_node().copied();
-        assert_eq!(highest_staked_node, Some(vote11_node_pubkey));
+        let mut expected = vec![
+            DelegationChange::Redelegated {
+                stake_pubkey: stake_pubkey1,
+                old_voter_pubkey: vote_pubkey1,
+                new_voter_pubkey: vote_pubkey2,
+                old_stake: 10,
+                new_stake: 10,
+            },
+            DelegationChange::StakeChanged {
+                stake_pubkey: stake_pubkey2,
+                voter_pubkey: vote_pubkey2,
+                old_stake: 20,
+                new_stake: 25,
+            },
+            DelegationChange::Removed {
+                stake_pubkey: stake_pubkey3,
+                voter_pubkey: vote_pubkey1,
+                stake: 30,
+            },
+            DelegationChange::Added {
+                stake_pubkey: stake_pubkey4,
+                voter_pubkey: vote_pubkey1,
+                stake: 50,
+            },
+        ];
+        let stake_pubkey = |change: &DelegationChange| match change {
+            DelegationChange::Added { stake_pubkey, .. }
+            | DelegationChange::Removed { stake_pubkey, .. }
+            | DelegationChange::Redelegated { stake_pubkey, .. }
+            | DelegationChange::StakeChanged { stake_pubkey, .. } => *stake_pubkey,
+        };
+        expected.sort_unstable_by_key(stake_pubkey);
+        assert_eq!(stakes.delegation_changes(&previous), expected);
     }
 
     #[test]
-    fn test_stakes_vote_account_disappear_reappear() {
-        let stakes_cache = StakesCache::new(Stakes {
-            epoch: 4,
-            ..Stakes::default()
-        });
+    fn test_lorenz_points() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert!(stakes.lorenz_points().is_empty());
+        for stake in [30, 10, 60] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        }
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
 
+        let points = stakes.lorenz_points();
+        let expected = [(0.0, 0.0), (0.25, 0.0), (0.5, 0.1), (0.75, 0.4), (1.0, 1.0)];
+        assert_eq!(points.len(), expected.len());
+        for ((x, y), (expected_x, expected_y)) in points.into_iter().zip(expected) {
+            assert!((x - expected_x).abs() < 1e-9);
+            assert!((y - expected_y).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_vote_account_last_slot() {
         let ((vote_pubkey, mut vote_account), (stake_pubkey, stake_account)) =
             create_staked_node_accounts(10);
+        let mut stakes = Stakes::<StakeAccount>::default();
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        assert_eq!(stakes.vote_account_last_slot(&vote_pubkey), None);
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+        stakes.store_at_slot(&vote_pubkey, &vote_account, 100, None);
+        assert_eq!(stakes.vote_account_last_slot(&vote_pubkey), Some(100));
+        // storing a stake account does not record a slot.
+        stakes.store_at_slot(&stake_pubkey, &stake_account, 101, None);
+        assert_eq!(stakes.vote_account_last_slot(&stake_pubkey), None);
+        assert_eq!(stakes.vote_account_last_slot(&vote_pubkey), Some(100));
+        check(&stakes);
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
-        }
+        // storing without a slot clears the recorded slot.
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        assert_eq!(stakes.vote_account_last_slot(&vote_pubkey), None);
+
+        // the stakes cache records the slot of each stored vote account.
+        let stakes_cache = StakesCache::new(stakes.clone());
+        stakes_cache.check_and_store(&vote_pubkey, &vote_account, 100, None);
+        assert_eq!(
+            stakes_cache.stakes().vote_account_last_slot(&vote_pubkey),
+            Some(100)
+        );
+        // ... which no conversion to the other stakes formats carries over.
+        let delegations = Stakes::<Delegation>::from(stakes_cache.stakes().clone());
+        assert!(delegations.vote_account_slots.is_empty());
+        let mut stake_stakes = Stakes::<Stake>::from(stakes_cache.stakes().clone());
+        assert!(stake_stakes.vote_account_slots.is_empty());
+        stake_stakes.vote_account_slots.insert(vote_pubkey, 100);
+        let delegations = Stakes::<Delegation>::from(stake_stakes);
+        assert!(delegations.vote_account_slots.is_empty());
 
+        stakes.store_at_slot(&vote_pubkey, &vote_account, 102, None);
         vote_account.set_lamports(0);
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+        stakes.store_at_slot(&vote_pubkey, &vote_account, 103, None);
+        assert_eq!(stakes.vote_account_last_slot(&vote_pubkey), None);
+        check(&stakes);
+    }
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_none());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
-        }
+    #[test]
+    fn test_iter_vote_states() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1.clone()),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2.clone()),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let vote_states: Vec<_> = stakes
+            .iter_vote_states()
+            .map(|(vote_pubkey, stake, vote_state)| (vote_pubkey, stake, vote_state.clone()))
+            .sorted_unstable_by_key(|(_, stake, _)| *stake)
+            .collect();
+        assert_eq!(
+            vote_states,
+            vec![
+                (vote_pubkey1, 10, vote_state::from(&vote_account1).unwrap()),
+                (vote_pubkey2, 20, vote_state::from(&vote_account2).unwrap()),
+            ]
+        );
+    }
 
-        vote_account.set_lamports(1);
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+    #[test]
+    fn test_simulate_allocation() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let uncached_pubkey = solana_sdk::pubkey::new_rand();
+        let placeholder_pubkeys: Vec<_> = (0..4).map(|_| solana_sdk::pubkey::new_rand()).collect();
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
-        }
+        let simulated = stakes.simulate_allocation(
+            &[
+                (placeholder_pubkeys[0], vote_pubkey1, 5),
+                (placeholder_pubkeys[1], vote_pubkey2, 15),
+                (placeholder_pubkeys[2], uncached_pubkey, 7),
+                (placeholder_pubkeys[3], vote_pubkey1, 0),
+            ],
+            None,
+        );
+        let vote_accounts = simulated.vote_accounts();
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey1), 15);
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 35);
+        assert_eq!(vote_accounts.get_delegated_stake(&uncached_pubkey), 0);
+        assert_eq!(simulated.stake_delegations().len(), 5);
+        assert_eq!(
+            simulated.voter_of(&placeholder_pubkeys[1]),
+            Some(vote_pubkey2)
+        );
+        assert!(simulated
+            .stake_delegations()
+            .get(&placeholder_pubkeys[3])
+            .is_none());
+        check(&simulated);
 
-        // Vote account too big
-        let cache_data = vote_account.data().to_vec();
-        let mut pushed = vote_account.data().to_vec();
-        pushed.push(0);
-        vote_account.set_data(pushed);
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+        // allocating at a cached stake pubkey replaces its stake account.
+        let simulated = stakes.simulate_allocation(&[(stake_pubkey1, vote_pubkey2, 5)], None);
+        let vote_accounts = simulated.vote_accounts();
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey1), 0);
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 25);
+        check(&simulated);
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_none());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
-        }
+        // the original stakes are left untouched.
+        let vote_accounts = stakes.vote_accounts();
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey1), 10);
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 20);
+        assert_eq!(stakes.stake_delegations().len(), 2);
+    }
+
+    #[test]
+    fn test_unstaked_voters() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(10);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        assert!(stakes.unstaked_voters().is_empty());
+
+        let mut unstaked_pubkeys: Vec<_> = (0..2)
+            .map(|_| {
+                let vote_pubkey = solana_sdk::pubkey::new_rand();
+                let vote_account =
+                    vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+                stakes.check_and_store(&vote_pubkey, &vote_account, None);
+                vote_pubkey
+            })
+            .collect();
+        unstaked_pubkeys.sort_unstable();
+        assert_eq!(stakes.unstaked_voters(), unstaked_pubkeys);
+    }
+
+    #[test]
+    fn test_merge_with() {
+        let ((vote_pubkey, vote_account), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(20, &vote_pubkey, &stake_pubkey2);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account.clone()),
+            (stake_pubkey1, stake_account1),
+            (stake_pubkey2, stake_account2.clone()),
+        ]
+        .into_iter()
+        .collect();
+        let stake_pubkey3 = solana_sdk::pubkey::new_rand();
+        let overlay: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (
+                stake_pubkey1,
+                create_stake_account(15, &vote_pubkey, &stake_pubkey1),
+            ),
+            (
+                stake_pubkey2,
+                create_stake_account(25, &vote_pubkey, &stake_pubkey2),
+            ),
+            (
+                stake_pubkey3,
+                create_stake_account(5, &vote_pubkey, &stake_pubkey3),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let conflicts = RwLock::new(Vec::default());
+        stakes.merge_with(
+            &overlay,
+            |pubkey, _, _| {
+                conflicts.write().unwrap().push(*pubkey);
+                pubkey == &stake_pubkey1
+            },
+            None,
+        );
+        // identical accounts are not conflicts.
+        let mut conflicts = conflicts.into_inner().unwrap();
+        conflicts.sort_unstable();
+        let mut expected_conflicts = vec![stake_pubkey1, stake_pubkey2];
+        expected_conflicts.sort_unstable();
+        assert_eq!(conflicts, expected_conflicts);
 
-        // Vote account uninitialized
-        let default_vote_state = VoteState::default();
-        let versioned = VoteStateVersions::new_current(default_vote_state);
-        vote_state::to(&versioned, &mut vote_account).unwrap();
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 40);
+        assert_eq!(
+            stakes.stake_delegations()[&stake_pubkey2].account(),
+            &stake_account2
+        );
+        check(&stakes);
+    }
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_none());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+    #[test]
+    fn test_epoch_vote_accounts() {
+        let node_pubkey = solana_sdk::pubkey::new_rand();
+        let mut stakes = Stakes::<StakeAccount>::default();
+        let mut vote_accounts = Vec::default();
+        for stake in [10, 30, 0] {
+            let vote_pubkey = solana_sdk::pubkey::new_rand();
+            let vote_account = vote_state::create_account(&vote_pubkey, &node_pubkey, 0, 1);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            if stake != 0 {
+                let stake_pubkey = solana_sdk::pubkey::new_rand();
+                let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+                stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            }
+            vote_accounts.push(vote_account);
         }
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(20);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        let other_node_pubkey = vote_state::from(&vote_account).unwrap().node_pubkey;
+        // an unstaked node is excluded.
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+        stakes.check_and_store(&vote_pubkey, &vote_account, None);
+        check(&stakes);
 
-        vote_account.set_data(cache_data);
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+        let epoch_vote_accounts = stakes.epoch_vote_accounts();
+        assert_eq!(epoch_vote_accounts.len(), 2);
+        let (stake, vote_state) = &epoch_vote_accounts[&node_pubkey];
+        assert_eq!(*stake, 40);
+        assert_eq!(vote_state, &vote_state::from(&vote_accounts[1]).unwrap());
+        assert_eq!(epoch_vote_accounts[&other_node_pubkey].0, 20);
+    }
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+    #[test]
+    fn test_stake_above() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(stakes.stake_above(0), (0, 0));
+        for stake in [10, 20, 30, 40] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
         }
+        assert_eq!(stakes.stake_above(20), (3, 90));
+        assert_eq!(stakes.stake_above(21), (2, 70));
+        assert_eq!(stakes.stake_above(0), (4, 100));
+        assert_eq!(stakes.stake_above(41), (0, 0));
     }
 
     #[test]
-    fn test_stakes_change_delegate() {
-        let stakes_cache = StakesCache::new(Stakes {
-            epoch: 4,
-            ..Stakes::default()
+    fn test_stake_digest() {
+        let accounts: Vec<_> = [10, 20, 30]
+            .into_iter()
+            .flat_map(|stake| {
+                let (vote_account, stake_account) = create_staked_node_accounts(stake);
+                [vote_account, stake_account]
+            })
+            .collect();
+        let stakes: Stakes<StakeAccount> = accounts.iter().cloned().collect();
+        let reversed_stakes: Stakes<StakeAccount> = accounts.iter().rev().cloned().collect();
+        assert_eq!(stakes.stake_digest(), reversed_stakes.stake_digest());
+        assert_ne!(
+            stakes.stake_digest(),
+            Stakes::<StakeAccount>::default().stake_digest()
+        );
+
+        let (vote_pubkey, _) = &accounts[0];
+        let mut stakes = stakes;
+        let stake_pubkey = solana_sdk::pubkey::new_rand();
+        let stake_account = create_stake_account(1, vote_pubkey, &stake_pubkey);
+        stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        assert_ne!(stakes.stake_digest(), reversed_stakes.stake_digest());
+    }
+
+    #[test]
+    fn test_fold_delegations() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(
+            stakes.fold_delegations(0, |total, _, _, stake| total + stake),
+            0
+        );
+        let mut vote_pubkeys = Vec::default();
+        for stake in [10, 20, 30] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            vote_pubkeys.push(vote_pubkey);
+        }
+        let stake_pubkey = solana_sdk::pubkey::new_rand();
+        let stake_account = create_stake_account(40, &vote_pubkeys[0], &stake_pubkey);
+        stakes.check_and_store(&stake_pubkey, &stake_account, None);
+
+        let total_stake = stakes.fold_delegations(0, |total, _, _, stake| total + stake);
+        assert_eq!(total_stake, 100);
+        assert_eq!(total_stake, stakes.total_stake());
+        let stake_to_first_voter = stakes.fold_delegations(0, |total, _, voter_pubkey, stake| {
+            if voter_pubkey == vote_pubkeys[0] {
+                total + stake
+            } else {
+                total
+            }
         });
+        assert_eq!(stake_to_first_voter, 50);
+    }
 
-        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+    #[test]
+    fn test_from_parts_validated() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
             create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        let thread_pool = ThreadPoolBuilder::new().num_threads(2).build().unwrap();
 
-        let ((vote_pubkey2, vote_account2), (_stake_pubkey2, stake_account2)) =
-            create_staked_node_accounts(10);
+        let validated = Stakes::from_parts_validated(
+            stakes.epoch,
+            stakes.stake_history.clone(),
+            stakes.vote_accounts().clone(),
+            stakes.stake_delegations().clone(),
+            &thread_pool,
+            None,
+        )
+        .unwrap();
+        assert_eq!(validated, stakes);
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&vote_pubkey2, &vote_account2, None);
+        let mut vote_accounts = stakes.vote_accounts().clone();
+        vote_accounts.add_stake(&vote_pubkey2, 1);
+        assert!(matches!(
+            Stakes::from_parts_validated(
+                stakes.epoch,
+                stakes.stake_history.clone(),
+                vote_accounts,
+                stakes.stake_delegations().clone(),
+                &thread_pool,
+                None,
+            ),
+            Err(Error::VoteAccountStakeMismatch(vote_pubkey)) if vote_pubkey == vote_pubkey2
+        ));
+    }
 
-        // delegates to vote_pubkey
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+    #[test]
+    fn test_drain_stake_accounts() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(100);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let mut expected = vec![];
+        for stake in [1, 5, 50] {
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            if stake < 10 {
+                expected.push((stake_pubkey, stake_account));
+            }
+        }
+        expected.sort_unstable_by_key(|(pubkey, _)| *pubkey);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            156
+        );
 
-        let stake = stake_state::stake_from(&stake_account).unwrap();
+        let drained = stakes.drain_stake_accounts(|_, account| account.lamports() < 10, None);
+        assert_eq!(drained, expected);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey),
+            150
+        );
+        assert_eq!(stakes.stake_delegations().len(), 2);
+        check(&stakes);
+    }
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(
-                vote_accounts.get_delegated_stake(&vote_pubkey),
-                stake.stake(stakes.epoch, &stakes.stake_history, None)
-            );
-            assert!(vote_accounts.get(&vote_pubkey2).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 0);
-        }
+    #[test]
+    fn test_stake_trends() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let ((vote_pubkey3, vote_account3), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(30);
+        let previous: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2.clone()),
+            (vote_pubkey3, vote_account3.clone()),
+            (stake_pubkey3, stake_account3),
+        ]
+        .into_iter()
+        .collect();
+        assert!(previous
+            .stake_trends(&previous)
+            .values()
+            .all(|trend| trend == &StakeTrend::Flat));
 
-        // delegates to vote_pubkey2
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account2, None);
+        let mut stakes = previous.clone();
+        let stake_pubkey = solana_sdk::pubkey::new_rand();
+        let stake_account = create_stake_account(5, &vote_pubkey1, &stake_pubkey);
+        stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        let mut stake_account2 = stake_account2;
+        stake_account2.set_lamports(0);
+        stakes.check_and_store(&stake_pubkey2, &stake_account2, None);
+        let mut vote_account3 = vote_account3;
+        vote_account3.set_lamports(0);
+        stakes.check_and_store(&vote_pubkey3, &vote_account3, None);
+        let ((vote_pubkey4, vote_account4), (stake_pubkey4, stake_account4)) =
+            create_staked_node_accounts(40);
+        stakes.check_and_store(&vote_pubkey4, &vote_account4, None);
+        stakes.check_and_store(&stake_pubkey4, &stake_account4, None);
+        check(&stakes);
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
-            assert!(vote_accounts.get(&vote_pubkey2).is_some());
-            assert_eq!(
-                vote_accounts.get_delegated_stake(&vote_pubkey2),
-                stake.stake(stakes.epoch, &stakes.stake_history, None)
-            );
-        }
+        assert_eq!(
+            stakes.stake_trends(&previous),
+            HashMap::from([
+                (vote_pubkey1, StakeTrend::Up(5)),
+                (vote_pubkey2, StakeTrend::Down(20)),
+                (vote_pubkey3, StakeTrend::Down(30)),
+                (vote_pubkey4, StakeTrend::Up(40)),
+            ])
+        );
     }
-    #[test]
-    fn test_stakes_multiple_stakers() {
-        let stakes_cache = StakesCache::new(Stakes {
-            epoch: 4,
-            ..Stakes::default()
-        });
 
-        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+    #[test]
+    fn test_stake_accounts_by_staker() {
+        let ((vote_pubkey, vote_account), (stake_pubkey1, stake_account1)) =
             create_staked_node_accounts(10);
-
+        let staker = solana_sdk::pubkey::new_rand();
+        let set_staker = |stake_account: &mut AccountSharedData| {
+            let mut stake_state: StakeStateV2 = stake_account.state().unwrap();
+            let StakeStateV2::Stake(meta, _, _) = &mut stake_state else {
+                panic!("expected a delegated stake account");
+            };
+            meta.authorized.staker = staker;
+            stake_account.set_state(&stake_state).unwrap();
+        };
+        let mut stake_account1 = stake_account1;
+        set_staker(&mut stake_account1);
         let stake_pubkey2 = solana_sdk::pubkey::new_rand();
-        let stake_account2 = create_stake_account(10, &vote_pubkey, &stake_pubkey2);
+        let mut stake_account2 = create_stake_account(10, &vote_pubkey, &stake_pubkey2);
+        set_staker(&mut stake_account2);
+        let stake_pubkey3 = solana_sdk::pubkey::new_rand();
+        let stake_account3 = create_stake_account(10, &vote_pubkey, &stake_pubkey3);
+        let stakes_cache = StakesCache::default();
+        for (pubkey, account) in [
+            (vote_pubkey, vote_account),
+            (stake_pubkey1, stake_account1),
+            (stake_pubkey2, stake_account2),
+            (stake_pubkey3, stake_account3),
+        ] {
+            stakes_cache.check_and_store(&pubkey, &account, 0, None);
+        }
+        // undelegated stake accounts are included ...
+        let stake_pubkey4 = solana_sdk::pubkey::new_rand();
+        let stake_account4 = stake_state::create_lockup_stake_account(
+            &stake::state::Authorized::auto(&staker),
+            &stake::state::Lockup::default(),
+            &Rent::free(),
+            10,
+        );
+        stakes_cache.check_and_store(&stake_pubkey4, &stake_account4, 0, None);
+        // ... unless they have no authorized staker.
+        let uninitialized_account =
+            AccountSharedData::new(10, StakeStateV2::size_of(), &solana_stake_program::id());
+        stakes_cache.check_and_store(
+            &solana_sdk::pubkey::new_rand(),
+            &uninitialized_account,
+            0,
+            None,
+        );
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+        let mut stake_pubkeys = vec![stake_pubkey1, stake_pubkey2, stake_pubkey4];
+        stake_pubkeys.sort_unstable();
+        assert_eq!(
+            stakes_cache.stake_accounts_by_staker(),
+            HashMap::from([
+                (staker, stake_pubkeys),
+                (stake_pubkey3, vec![stake_pubkey3])
+            ])
+        );
+    }
 
-        // delegates to vote_pubkey
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
-        stakes_cache.check_and_store(&stake_pubkey2, &stake_account2, None);
+    #[test]
+    fn test_project_next_epoch() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(1_000);
+        let (warming_pubkey, warming_account) =
+            create_warming_stake_account(1_000, 0, &vote_pubkey1);
+        let ((vote_pubkey2, vote_account2), (cooling_pubkey, mut cooling_account)) =
+            create_staked_node_accounts(1_000);
+        let mut stake_state: StakeStateV2 = cooling_account.state().unwrap();
+        let StakeStateV2::Stake(_, stake, _) = &mut stake_state else {
+            panic!("expected a delegated stake account");
+        };
+        stake.deactivate(0).unwrap();
+        cooling_account.set_state(&stake_state).unwrap();
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (warming_pubkey, warming_account),
+            (vote_pubkey2, vote_account2),
+            (cooling_pubkey, cooling_account),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey1),
+            1_000
+        );
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            1_000
+        );
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 20);
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let projected = stakes.project_next_epoch(&thread_pool, None);
+        assert_eq!(projected.epoch, 1);
+        assert!(projected.vote_accounts().get_delegated_stake(&vote_pubkey1) > 1_000);
+        assert!(projected.vote_accounts().get_delegated_stake(&vote_pubkey2) < 1_000);
+        check(&projected);
+
+        // the original stakes are left untouched.
+        assert_eq!(stakes.epoch, 0);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey1),
+            1_000
+        );
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            1_000
+        );
+    }
+
+    #[test]
+    fn test_tail_stake() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        assert_eq!(stakes.tail_stake(0), 0);
+        for stake in [30, 10, 40, 20] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
         }
+        assert_eq!(stakes.tail_stake(0), 100);
+        assert_eq!(stakes.tail_stake(1), 60);
+        assert_eq!(stakes.tail_stake(3), 10);
+        assert_eq!(stakes.tail_stake(4), 0);
+        assert_eq!(stakes.tail_stake(usize::MAX), 0);
     }
 
     #[test]
-    fn test_activate_epoch() {
-        let stakes_cache = StakesCache::default();
+    fn test_stakes_builder() {
+        let vote_pubkey1 = solana_sdk::pubkey::new_rand();
+        let node_pubkey1 = solana_sdk::pubkey::new_rand();
+        let vote_pubkey2 = solana_sdk::pubkey::new_rand();
+        let node_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stakes = StakesBuilder::default()
+            .with_epoch(3)
+            .with_validator(vote_pubkey1, node_pubkey1, 5)
+            .with_validator(vote_pubkey2, node_pubkey2, 10)
+            .with_delegation(vote_pubkey1, 10)
+            .with_delegation(vote_pubkey1, 20)
+            .with_delegation(vote_pubkey2, 5)
+            .build();
 
+        assert_eq!(stakes.epoch, 3);
+        assert_eq!(stakes.total_stake(), 35);
+        assert_eq!(stakes.stake_delegations().len(), 3);
+        let vote_accounts = stakes.vote_accounts();
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey1), 30);
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 5);
+        let vote_state = vote_accounts.get(&vote_pubkey2).unwrap().vote_state();
+        assert_eq!(vote_state.node_pubkey, node_pubkey2);
+        assert_eq!(vote_state.commission, 10);
+        assert_eq!(stakes.highest_staked_node(), Some(&node_pubkey1));
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_delegated_by_activation_epoch() {
         let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            create_staked_node_accounts(100);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        for (stake, epoch) in [(10, 3), (20, 5), (30, 3)] {
+            let (stake_pubkey, stake_account) =
+                create_warming_stake_account(stake, epoch, &vote_pubkey);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+        }
+        assert_eq!(
+            stakes.delegated_by_activation_epoch(),
+            BTreeMap::from([(3, 40), (5, 20), (Epoch::MAX, 100)])
+        );
+    }
+
+    #[test]
+    fn test_reconcile_voter() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
             create_staked_node_accounts(10);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey2, stake_account2)) =
+            create_staked_node_accounts(20);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey2, stake_account2),
+        ]
+        .into_iter()
+        .collect();
+        stakes.force_set_stake(&vote_pubkey1, 15);
+        stakes.force_set_stake(&vote_pubkey2, 25);
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
-        let stake = stake_state::stake_from(&stake_account).unwrap();
+        stakes.reconcile_voter(&vote_pubkey1, None);
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey1),
+            10
+        );
+        assert_eq!(
+            stakes.vote_accounts().get_delegated_stake(&vote_pubkey2),
+            25
+        );
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert_eq!(
-                vote_accounts.get_delegated_stake(&vote_pubkey),
-                stake.stake(stakes.epoch, &stakes.stake_history, None)
-            );
+        stakes.force_set_stake(&vote_pubkey1, 5);
+        stakes.reconcile_voter(&vote_pubkey1, None);
+        stakes.reconcile_voter(&vote_pubkey2, None);
+        check(&stakes);
+        // vote accounts which are not cached are ignored.
+        stakes.reconcile_voter(&solana_sdk::pubkey::new_rand(), None);
+        check(&stakes);
+    }
+
+    #[test]
+    fn test_staked_node_pubkeys_sorted() {
+        let node_pubkey = solana_sdk::pubkey::new_rand();
+        let mut accounts = vec![];
+        // two vote accounts with the same node identity.
+        for stake in [10, 20] {
+            let vote_pubkey = solana_sdk::pubkey::new_rand();
+            let vote_account = vote_state::create_account(&vote_pubkey, &node_pubkey, 0, 1);
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+            accounts.push((vote_pubkey, vote_account));
+            accounts.push((stake_pubkey, stake_account));
         }
-        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
-        stakes_cache.activate_epoch(3, &thread_pool, None);
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert_eq!(
-                vote_accounts.get_delegated_stake(&vote_pubkey),
-                stake.stake(stakes.epoch, &stakes.stake_history, None)
-            );
+        let mut node_pubkeys = vec![];
+        for stake in [40, 20, 20] {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            node_pubkeys.push(vote_state::from(&vote_account).unwrap().node_pubkey);
+            accounts.push((vote_pubkey, vote_account));
+            accounts.push((stake_pubkey, stake_account));
         }
+        // an unstaked node is excluded.
+        let vote_pubkey = solana_sdk::pubkey::new_rand();
+        let vote_account =
+            vote_state::create_account(&vote_pubkey, &solana_sdk::pubkey::new_rand(), 0, 1);
+        accounts.push((vote_pubkey, vote_account));
+        let stakes: Stakes<StakeAccount> = accounts.iter().cloned().collect();
+        let reversed_stakes: Stakes<StakeAccount> = accounts.iter().rev().cloned().collect();
+
+        let (tied_first, tied_second) = if node_pubkeys[1] < node_pubkeys[2] {
+            (node_pubkeys[1], node_pubkeys[2])
+        } else {
+            (node_pubkeys[2], node_pubkeys[1])
+        };
+        let expected = vec![
+            (node_pubkeys[0], 40),
+            (node_pubkey, 30),
+            (tied_first, 20),
+            (tied_second, 20),
+        ];
+        assert_eq!(stakes.staked_node_pubkeys_sorted(), expected);
+        assert_eq!(reversed_stakes.staked_node_pubkeys_sorted(), expected);
     }
 
     #[test]
-    fn test_stakes_not_delegate() {
-        let stakes_cache = StakesCache::new(Stakes {
-            epoch: 4,
-            ..Stakes::default()
-        });
+    fn test_apply_inflation() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(100);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(200, &vote_pubkey1, &stake_pubkey2);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(50);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1),
+            (stake_pubkey1, stake_account1),
+            (stake_pubkey2, stake_account2),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey3, stake_account3),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(stakes.apply_inflation(-0.1, None), 0);
+        assert_eq!(stakes.apply_inflation(f64::NAN, None), 0);
+        assert_eq!(stakes.total_stake(), 350);
+
+        assert_eq!(stakes.apply_inflation(0.1, None), 35);
+        let vote_accounts = stakes.vote_accounts();
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey1), 330);
+        assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey2), 55);
+        let stake_account = &stakes.stake_delegations()[&stake_pubkey2];
+        assert_eq!(stake_account.delegation().stake, 220);
+        assert_eq!(stake_account.lamports(), 220);
+        check(&stakes);
+    }
 
+    #[test]
+    fn test_duplicate_account_groups() {
         let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
             create_staked_node_accounts(10);
+        let mut stakes: Stakes<StakeAccount> = [
+            (vote_pubkey, vote_account),
+            (stake_pubkey, stake_account.clone()),
+        ]
+        .into_iter()
+        .collect();
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        stakes.check_and_store(
+            &stake_pubkey2,
+            &create_stake_account(20, &vote_pubkey, &stake_pubkey2),
+            None,
+        );
+        assert!(stakes.duplicate_account_groups().is_empty());
 
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+        let duplicate_pubkey = solana_sdk::pubkey::new_rand();
+        stakes.check_and_store(&duplicate_pubkey, &stake_account, None);
+        let mut expected = vec![stake_pubkey, duplicate_pubkey];
+        expected.sort_unstable();
+        assert_eq!(stakes.duplicate_account_groups(), vec![expected]);
+        // identical accounts are still counted separately.
+        assert_eq!(stakes.vote_accounts().get_delegated_stake(&vote_pubkey), 40);
+    }
 
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 10);
+    #[test]
+    fn test_shard_by_voter() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        for stake in 1..=10 {
+            let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+                create_staked_node_accounts(stake);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
         }
+        check(&stakes);
+        assert!(stakes.shard_by_voter(0).is_empty());
 
-        // not a stake account, and whacks above entry
-        stakes_cache.check_and_store(
-            &stake_pubkey,
-            &AccountSharedData::new(1, 0, &stake::program::id()),
-            None,
-        );
-        {
-            let stakes = stakes_cache.stakes();
-            let vote_accounts = stakes.vote_accounts();
-            assert!(vote_accounts.get(&vote_pubkey).is_some());
-            assert_eq!(vote_accounts.get_delegated_stake(&vote_pubkey), 0);
+        let shards = stakes.shard_by_voter(4);
+        assert_eq!(shards.len(), 4);
+        for shard in &shards {
+            check(shard);
         }
+        assert_eq!(
+            shards
+                .iter()
+                .map(|shard| shard.vote_accounts().len())
+                .sum::<usize>(),
+            10
+        );
+        let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        assert_eq!(Stakes::concat(shards, &thread_pool, None), stakes);
     }
 
     #[test]
-    fn test_vote_balance_and_staked_empty() {
-        let stakes = Stakes::<StakeAccount>::default();
-        assert_eq!(stakes.vote_balance_and_staked(), 0);
+    fn test_post_commission_stake() {
+        let mut stakes = Stakes::<StakeAccount>::default();
+        let mut vote_pubkeys = vec![];
+        for commission in [0, 10, 100] {
+            let vote_pubkey = solana_sdk::pubkey::new_rand();
+            let vote_account = vote_state::create_account(
+                &vote_pubkey,
+                &solana_sdk::pubkey::new_rand(),
+                commission,
+                1,
+            );
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(1_005, &vote_pubkey, &stake_pubkey);
+            stakes.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes.check_and_store(&stake_pubkey, &stake_account, None);
+            vote_pubkeys.push(vote_pubkey);
+        }
+        assert_eq!(
+            stakes.post_commission_stake(),
+            HashMap::from([
+                (vote_pubkeys[0], 1_005),
+                (vote_pubkeys[1], 904),
+                (vote_pubkeys[2], 0),
+            ])
+        );
     }
 
     #[test]
-    fn test_vote_balance_and_staked_normal() {
-        let stakes_cache = StakesCache::default();
-        #[allow(non_local_definitions)]
-        impl Stakes<StakeAccount> {
-            fn vote_balance_and_warmed_staked(&self) -> u64 {
-                let vote_balance: u64 = self
-                    .vote_accounts
-                    .iter()
-                    .map(|(_pubkey, account)| account.lamports())
-                    .sum();
-                let warmed_stake: u64 = self
-                    .vote_accounts
-                    .delegated_stakes()
-                    .map(|(_pubkey, stake)| stake)
-                    .sum();
-                vote_balance + warmed_stake
-            }
-        }
-
-        let genesis_epoch = 0;
-        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
-            create_warming_staked_node_accounts(10, genesis_epoch);
-        stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
-        stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+    fn test_observe_total_stake_change() {
+        let ((vote_pubkey, vote_account), (bootstrap_stake_pubkey, bootstrap_stake_account)) =
+            create_staked_node_accounts(100);
+        let stakes_cache = StakesCache::new(
+            [
+                (vote_pubkey, vote_account),
+                (bootstrap_stake_pubkey, bootstrap_stake_account.clone()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let changes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        stakes_cache.observe_total_stake_change(0.1, {
+            let changes = changes.clone();
+            move |old, new| changes.lock().unwrap().push((old, new))
+        });
 
-        {
-            let stakes = stakes_cache.stakes();
-            assert_eq!(stakes.vote_balance_and_staked(), 11);
-            assert_eq!(stakes.vote_balance_and_warmed_staked(), 1);
-        }
+        let store_stake = |stakes_cache: &StakesCache, stake| {
+            let stake_pubkey = solana_sdk::pubkey::new_rand();
+            let stake_account = create_stake_account(stake, &vote_pubkey, &stake_pubkey);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+            (stake_pubkey, stake_account)
+        };
+        // within 10% of 100.
+        store_stake(&stakes_cache, 5);
+        assert!(changes.lock().unwrap().is_empty());
+        let (stake_pubkey, mut stake_account) = store_stake(&stakes_cache, 10);
+        assert_eq!(*changes.lock().unwrap(), vec![(100, 115)]);
+        // within 10% of 115.
+        stake_account.set_lamports(0);
+        stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        assert_eq!(*changes.lock().unwrap(), vec![(100, 115)]);
+        // the bootstrap stake account is removed.
+        let mut bootstrap_stake_account = bootstrap_stake_account;
+        bootstrap_stake_account.set_lamports(0);
+        stakes_cache.check_and_store(&bootstrap_stake_pubkey, &bootstrap_stake_account, 0, None);
+        assert_eq!(*changes.lock().unwrap(), vec![(100, 115), (115, 5)]);
+        check(&stakes_cache.stakes());
 
+        // the observer is carried over to child banks
+        let child_stakes_cache = StakesCache::new_from_parent(&stakes_cache);
+        store_stake(&child_stakes_cache, 100);
+        assert_eq!(child_stakes_cache.stakes().total_stake(), 105);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(100, 115), (115, 5), (5, 105)]
+        );
+        // ... and keeps its own total stake, which matches the stakes.
         let thread_pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
-        for (epoch, expected_warmed_stake) in ((genesis_epoch + 1)..=3).zip(&[2, 3, 4]) {
-            stakes_cache.activate_epoch(epoch, &thread_pool, None);
-            // vote_balance_and_staked() always remain to return same lamports
-            // while vote_balance_and_warmed_staked() gradually increases
-            let stakes = stakes_cache.stakes();
-            assert_eq!(stakes.vote_balance_and_staked(), 11);
-            assert_eq!(
-                stakes.vote_balance_and_warmed_staked(),
-                *expected_warmed_stake
-            );
-        }
+        child_stakes_cache.activate_epoch(1, &thread_pool, None);
+        store_stake(&stakes_cache, 1);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![(100, 115), (115, 5), (5, 105), (5, 6)]
+        );
+    }
+
+    #[test]
+    fn test_accounts_for_voter() {
+        let ((vote_pubkey1, vote_account1), (stake_pubkey1, stake_account1)) =
+            create_staked_node_accounts(10);
+        let stake_pubkey2 = solana_sdk::pubkey::new_rand();
+        let stake_account2 = create_stake_account(20, &vote_pubkey1, &stake_pubkey2);
+        let ((vote_pubkey2, vote_account2), (stake_pubkey3, stake_account3)) =
+            create_staked_node_accounts(30);
+        let uncached_pubkey = solana_sdk::pubkey::new_rand();
+        let stake_pubkey4 = solana_sdk::pubkey::new_rand();
+        let stake_account4 = create_stake_account(40, &uncached_pubkey, &stake_pubkey4);
+        let stakes: Stakes<StakeAccount> = [
+            (vote_pubkey1, vote_account1.clone()),
+            (stake_pubkey1, stake_account1.clone()),
+            (stake_pubkey2, stake_account2.clone()),
+            (vote_pubkey2, vote_account2),
+            (stake_pubkey3, stake_account3),
+            (stake_pubkey4, stake_account4.clone()),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut stake_accounts = vec![
+            (stake_pubkey1, stake_account1),
+            (stake_pubkey2, stake_account2),
+        ];
+        stake_accounts.sort_unstable_by_key(|(stake_pubkey, _)| *stake_pubkey);
+        let mut expected = vec![(vote_pubkey1, vote_account1)];
+        expected.extend(stake_accounts);
+        assert_eq!(stakes.accounts_for_voter(&vote_pubkey1), expected);
+        assert_eq!(
+            stakes.accounts_for_voter(&uncached_pubkey),
+            vec![(stake_pubkey4, stake_account4)]
+        );
+        assert!(stakes
+            .accounts_for_voter(&solana_sdk::pubkey::new_rand())
+            .is_empty());
     }
 }
@@ -1,12 +1,18 @@
 use {
-    super::{StakeAccount, Stakes, StakesEnum},
+    super::{Error, StakeAccount, Stakes, StakesEnum},
     crate::stake_history::StakeHistory,
     im::HashMap as ImHashMap,
     serde::{ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer},
-    solana_sdk::{clock::Epoch, pubkey::Pubkey, stake::state::Delegation},
+    solana_sdk::{
+        account::{AccountSharedData, WritableAccount},
+        clock::Epoch,
+        hash::{hash, Hash},
+        pubkey::Pubkey,
+        stake::state::Delegation,
+    },
     solana_stake_program::stake_state::Stake,
-    solana_vote::vote_account::VoteAccounts,
-    std::sync::Arc,
+    solana_vote::vote_account::{VoteAccount, VoteAccounts},
+    std::{collections::HashMap, sync::Arc},
 };
 
 /// Wrapper struct with custom serialization to support serializing
@@ -66,6 +72,152 @@ impl<'de> Deserialize<'de> for SerdeStakesToStakeFormat {
     }
 }
 
+impl Stakes<StakeAccount> {
+    /// Returns the size of these stakes once serialized in the
+    /// `Stakes<Delegation>` format used by bank snapshots, without serializing
+    /// into a buffer.
+    pub fn serialized_size(&self) -> bincode::Result<u64> {
+        bincode::serialized_size(&SerdeStakeAccountsToDelegationFormat::from(self.clone()))
+    }
+
+    /// Serializes these stakes in the `Stakes<Delegation>` format used by
+    /// bank snapshots, with the rent_epoch of the cached vote accounts
+    /// zeroed out, so that stakes which differ only in that field serialize
+    /// to identical bytes.
+    pub fn serialize_canonical(&self) -> bincode::Result<Vec<u8>> {
+        let mut stakes = self.clone();
+        stakes.vote_accounts = self
+            .vote_accounts
+            .iter()
+            .map(|(vote_pubkey, vote_account)| {
+                let mut account = AccountSharedData::from(vote_account.clone());
+                account.set_rent_epoch(Epoch::default());
+                // Safe to unwrap because the account data is unchanged.
+                let vote_account = VoteAccount::try_from(account).unwrap();
+                let stake = self.vote_accounts.get_delegated_stake(vote_pubkey);
+                (*vote_pubkey, (stake, vote_account))
+            })
+            .collect();
+        bincode::serialize(&SerdeStakeAccountsToDelegationFormat::from(stakes))
+    }
+}
+
+/// Stakes serialized in the `Stakes<Delegation>` format, along with a hash of
+/// the serialized bytes, see Stakes::serialize_checked.
+#[derive(Deserialize, Serialize)]
+struct CheckedStakes {
+    version: u32,
+    checksum: Hash,
+    stakes: Vec<u8>,
+}
+
+impl CheckedStakes {
+    const VERSION: u32 = 1;
+}
+
+impl Stakes<StakeAccount> {
+    /// Same as serializing in the `Stakes<Delegation>` format used by bank
+    /// snapshots, but also embeds a hash of the serialized stakes, so that
+    /// corrupted bytes are rejected by Stakes::deserialize_checked. The
+    /// hash, unlike Stakes::checksum, is stable across processes.
+    pub fn serialize_checked(&self) -> bincode::Result<Vec<u8>> {
+        let stakes = bincode::serialize(&SerdeStakeAccountsToDelegationFormat::from(self.clone()))?;
+        bincode::serialize(&CheckedStakes {
+            version: CheckedStakes::VERSION,
+            checksum: hash(&stakes),
+            stakes,
+        })
+    }
+
+    /// Writes these stakes in the `Stakes<Delegation>` format used by bank
+    /// snapshots, compressed with zstd at the given level. Unlike
+    /// serialize_canonical, the vote accounts are written as they are, so
+    /// their rent epoch survives the round trip.
+    #[cfg(feature = "zstd")]
+    pub fn serialize_compressed<W: std::io::Write>(
+        &self,
+        writer: W,
+        level: i32,
+    ) -> Result<(), Error> {
+        let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
+        bincode::serialize_into(
+            &mut encoder,
+            &SerdeStakeAccountsToDelegationFormat::from(self.clone()),
+        )?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+impl Stakes<Delegation> {
+    /// Reads stakes written by Stakes::serialize_compressed, verifying them
+    /// the same as Stakes::try_from_snapshot_bytes.
+    #[cfg(feature = "zstd")]
+    pub fn deserialize_compressed<R: std::io::Read>(
+        reader: R,
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Result<Self, Error> {
+        let mut stakes = Vec::new();
+        std::io::copy(&mut zstd::stream::read::Decoder::new(reader)?, &mut stakes)?;
+        Self::try_from_snapshot_bytes(&stakes, new_rate_activation_epoch)
+    }
+
+    /// Deserializes stakes serialized by Stakes::serialize_checked, verifying
+    /// the embedded hash before verifying the stakes the same as
+    /// Stakes::try_from_snapshot_bytes.
+    pub fn deserialize_checked(
+        bytes: &[u8],
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Result<Self, Error> {
+        let CheckedStakes {
+            version,
+            checksum,
+            stakes,
+        } = bincode::deserialize(bytes)?;
+        if version != CheckedStakes::VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if hash(&stakes) != checksum {
+            return Err(Error::ChecksumMismatch);
+        }
+        Self::try_from_snapshot_bytes(&stakes, new_rate_activation_epoch)
+    }
+
+    /// Deserializes stakes in the `Stakes<Delegation>` format used by bank
+    /// snapshots, and verifies that the delegated stake of each vote account
+    /// matches the stake recalculated from the stake delegations.
+    pub fn try_from_snapshot_bytes(
+        bytes: &[u8],
+        new_rate_activation_epoch: Option<Epoch>,
+    ) -> Result<Self, Error> {
+        let stakes: Self = bincode::deserialize(bytes)?;
+        let mut delegated_stakes = HashMap::<Pubkey, u64>::new();
+        for delegation in stakes.stake_delegations.values() {
+            let stake = delegation.stake(
+                stakes.epoch,
+                &stakes.stake_history,
+                new_rate_activation_epoch,
+            );
+            let voter = delegation.voter_pubkey;
+            let delegated_stake = delegated_stakes.entry(voter).or_default();
+            *delegated_stake = delegated_stake
+                .checked_add(stake)
+                .ok_or(Error::DelegatedStakeOverflow { voter })?;
+        }
+        for (vote_pubkey, stake) in stakes.vote_accounts.delegated_stakes() {
+            if delegated_stakes
+                .get(vote_pubkey)
+                .copied()
+                .unwrap_or_default()
+                != stake
+            {
+                return Err(Error::VoteAccountStakeMismatch(*vote_pubkey));
+            }
+        }
+        Ok(stakes)
+    }
+}
+
 // In order to maintain backward compatibility, the StakesEnum in EpochStakes
 // and SerializableVersionedBank should be serialized as Stakes<Delegation>.
 pub(crate) mod serde_stakes_to_delegation_format {
@@ -125,6 +277,8 @@ impl From<Stakes<Stake>> for SerdeStakesToDelegationFormat {
             unused,
             epoch,
             stake_history,
+            vote_account_slots: _,
+            idle_epochs: _,
         } = stakes;
 
         Self {
@@ -145,6 +299,8 @@ impl From<Stakes<StakeAccount>> for SerdeStakeAccountsToDelegationFormat {
             unused,
             epoch,
             stake_history,
+            vote_account_slots: _,
+            idle_epochs: _,
         } = stakes;
 
         Self {
@@ -165,6 +321,8 @@ impl From<Stakes<StakeAccount>> for SerdeStakeAccountsToStakeFormat {
             unused,
             epoch,
             stake_history,
+            vote_account_slots: _,
+            idle_epochs: _,
         } = stakes;
 
         Self {
@@ -285,6 +443,8 @@ mod tests {
             unused: 0,
             epoch: 0,
             stake_history: StakeHistory::default(),
+            vote_account_slots: ImHashMap::default(),
+            idle_epochs: ImHashMap::default(),
         };
 
         let wrapped_stakes = SerdeStakesToStakeFormat::Account(stake_account_stakes.clone());
@@ -319,7 +479,7 @@ mod tests {
                 rng.gen_range(0..101),           // commission
                 rng.gen_range(0..1_000_000),     // lamports
             );
-            stakes_cache.check_and_store(&vote_pubkey, &vote_account, None);
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
             for _ in 0..rng.gen_range(10usize..20) {
                 let stake_pubkey = solana_sdk::pubkey::new_rand();
                 let rent = Rent::with_slots_per_epoch(rng.gen());
@@ -330,7 +490,7 @@ mod tests {
                     &rent,
                     rng.gen_range(0..1_000_000), // lamports
                 );
-                stakes_cache.check_and_store(&stake_pubkey, &stake_account, None);
+                stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
             }
         }
         let stakes: Stakes<StakeAccount> = stakes_cache.stakes().clone();
@@ -354,4 +514,192 @@ mod tests {
         };
         assert_eq!(other, &stakes)
     }
+
+    #[test]
+    fn test_serialized_size() {
+        let stakes_cache = StakesCache::default();
+        assert_eq!(
+            stakes_cache.stakes().serialized_size().unwrap(),
+            bincode::serialize(&Stakes::<Delegation>::default())
+                .unwrap()
+                .len() as u64
+        );
+        for _ in 0..5 {
+            let vote_pubkey = solana_sdk::pubkey::new_rand();
+            let vote_account = vote_state::create_account(
+                &vote_pubkey,
+                &solana_sdk::pubkey::new_rand(), // node_pubkey
+                0,                               // commission
+                1_000_000,                       // lamports
+            );
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            for _ in 0..3 {
+                let stake_pubkey = solana_sdk::pubkey::new_rand();
+                let stake_account = stake_state::create_account(
+                    &stake_pubkey, // authorized
+                    &vote_pubkey,
+                    &vote_account,
+                    &Rent::default(),
+                    1_000_000_000, // lamports
+                );
+                stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+            }
+        }
+        let stakes: Stakes<StakeAccount> = stakes_cache.stakes().clone();
+        let serialized = bincode::serialize(&Stakes::<Delegation>::from(stakes.clone())).unwrap();
+        assert_eq!(stakes.serialized_size().unwrap(), serialized.len() as u64);
+    }
+
+    #[test]
+    fn test_serialize_canonical() {
+        let stakes_cache = StakesCache::default();
+        for _ in 0..3 {
+            let vote_pubkey = Pubkey::new_unique();
+            let vote_account = vote_state::create_account(
+                &vote_pubkey,
+                &Pubkey::new_unique(), // node_pubkey
+                0,                     // commission
+                1_000_000,             // lamports
+            );
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            let stake_pubkey = Pubkey::new_unique();
+            let stake_account = stake_state::create_account(
+                &stake_pubkey, // authorized
+                &vote_pubkey,
+                &vote_account,
+                &Rent::default(),
+                1_000_000,
+            );
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        }
+        let stakes = stakes_cache.stakes().clone();
+        let mut other = stakes.clone();
+        for vote_pubkey in stakes.vote_accounts().iter().map(|(pubkey, _)| *pubkey) {
+            let mut account =
+                AccountSharedData::from(stakes.vote_accounts().get(&vote_pubkey).unwrap().clone());
+            account.set_rent_epoch(42);
+            other.check_and_store(&vote_pubkey, &account, None);
+        }
+        assert_ne!(
+            bincode::serialize(&SerdeStakeAccountsToDelegationFormat::from(stakes.clone()))
+                .unwrap(),
+            bincode::serialize(&SerdeStakeAccountsToDelegationFormat::from(other.clone())).unwrap()
+        );
+        assert_eq!(
+            stakes.serialize_canonical().unwrap(),
+            other.serialize_canonical().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_try_from_snapshot_bytes() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            crate::stakes::tests::create_staked_node_accounts(10);
+        let mut stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let bytes = bincode::serialize(&SerdeStakeAccountsToDelegationFormat::from(stakes.clone()))
+            .unwrap();
+        assert_eq!(
+            Stakes::<Delegation>::try_from_snapshot_bytes(&bytes, None).unwrap(),
+            Stakes::<Delegation>::from(stakes.clone())
+        );
+
+        // truncated
+        assert!(matches!(
+            Stakes::<Delegation>::try_from_snapshot_bytes(&bytes[..bytes.len() - 1], None),
+            Err(Error::DeserializeError(_))
+        ));
+
+        // the cached stake is not backed by the stake delegations
+        stakes.force_set_stake(&vote_pubkey, 11);
+        let bytes =
+            bincode::serialize(&SerdeStakeAccountsToDelegationFormat::from(stakes)).unwrap();
+        assert!(matches!(
+            Stakes::<Delegation>::try_from_snapshot_bytes(&bytes, None),
+            Err(Error::VoteAccountStakeMismatch(pubkey)) if pubkey == vote_pubkey
+        ));
+
+        // the stake delegated to a single voter overflows
+        let delegation = Delegation {
+            voter_pubkey: vote_pubkey,
+            stake: u64::MAX,
+            activation_epoch: Epoch::MAX,
+            ..Delegation::default()
+        };
+        let stakes = Stakes::<Delegation> {
+            stake_delegations: [
+                (Pubkey::new_unique(), delegation),
+                (Pubkey::new_unique(), delegation),
+            ]
+            .into_iter()
+            .collect(),
+            ..Stakes::default()
+        };
+        let bytes = bincode::serialize(&stakes).unwrap();
+        assert!(matches!(
+            Stakes::<Delegation>::try_from_snapshot_bytes(&bytes, None),
+            Err(Error::DelegatedStakeOverflow { voter }) if voter == vote_pubkey
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_checked() {
+        let ((vote_pubkey, vote_account), (stake_pubkey, stake_account)) =
+            crate::stakes::tests::create_staked_node_accounts(10);
+        let stakes: Stakes<StakeAccount> =
+            [(vote_pubkey, vote_account), (stake_pubkey, stake_account)]
+                .into_iter()
+                .collect();
+        let mut bytes = stakes.serialize_checked().unwrap();
+        assert_eq!(
+            Stakes::<Delegation>::deserialize_checked(&bytes, None).unwrap(),
+            Stakes::<Delegation>::from(stakes)
+        );
+
+        // The stakes are serialized last, ending with the epoch and then the
+        // length of the empty stake history. Flipping a bit of the epoch
+        // still yields valid stakes, but not the embedded hash.
+        let offset = bytes.len() - 2 * std::mem::size_of::<u64>();
+        bytes[offset] ^= 1;
+        let CheckedStakes { stakes, .. } = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(
+            Stakes::<Delegation>::try_from_snapshot_bytes(&stakes, None)
+                .unwrap()
+                .epoch,
+            1
+        );
+        assert!(matches!(
+            Stakes::<Delegation>::deserialize_checked(&bytes, None),
+            Err(Error::ChecksumMismatch)
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_serialize_compressed() {
+        use solana_sdk::account::ReadableAccount;
+
+        let stakes_cache = StakesCache::default();
+        for _ in 0..3 {
+            let ((vote_pubkey, mut vote_account), (stake_pubkey, stake_account)) =
+                crate::stakes::tests::create_staked_node_accounts(1_000);
+            vote_account.set_rent_epoch(42);
+            stakes_cache.check_and_store(&vote_pubkey, &vote_account, 0, None);
+            stakes_cache.check_and_store(&stake_pubkey, &stake_account, 0, None);
+        }
+        let stakes = stakes_cache.stakes().clone();
+        let mut buffer = Vec::new();
+        stakes.serialize_compressed(&mut buffer, 3).unwrap();
+        let other = Stakes::<Delegation>::deserialize_compressed(buffer.as_slice(), None).unwrap();
+        for (_, vote_account) in other.vote_accounts().iter() {
+            assert_eq!(vote_account.account().rent_epoch(), 42);
+        }
+        assert_eq!(other, Stakes::<Delegation>::from(stakes));
+        assert!(
+            Stakes::<Delegation>::deserialize_compressed(&buffer[..buffer.len() / 2], None)
+                .is_err()
+        );
+    }
 }
@@ -233,6 +233,19 @@ impl VoteAccounts {
         entry
     }
 
+    /// Shrinks the capacity of the underlying maps as much as possible, e.g.
+    /// after many vote accounts have been removed. Maps which are shared with
+    /// clones of these vote accounts are left as is, since shrinking them
+    /// would first copy the whole map.
+    pub fn shrink_to_fit(&mut self) {
+        if let Some(vote_accounts) = Arc::get_mut(&mut self.vote_accounts) {
+            vote_accounts.shrink_to_fit();
+        }
+        if let Some(staked_nodes) = self.staked_nodes.get_mut().and_then(Arc::get_mut) {
+            staked_nodes.shrink_to_fit();
+        }
+    }
+
     pub fn add_stake(&mut self, pubkey: &Pubkey, delta: u64) {
         let vote_accounts = Arc::make_mut(&mut self.vote_accounts);
         if let Some((stake, vote_account)) = vote_accounts.get_mut(pubkey) {